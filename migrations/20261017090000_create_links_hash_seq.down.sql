@@ -0,0 +1 @@
+DROP SEQUENCE links_hash_seq;
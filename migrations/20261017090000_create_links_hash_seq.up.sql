@@ -0,0 +1 @@
+CREATE SEQUENCE links_hash_seq AS bigint MINVALUE 1;
@@ -0,0 +1,2 @@
+DROP INDEX links_destination;
+CREATE UNIQUE INDEX links_destination ON links (destination);
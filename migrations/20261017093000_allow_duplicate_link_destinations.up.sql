@@ -0,0 +1,2 @@
+DROP INDEX links_destination;
+CREATE INDEX links_destination ON links (destination);
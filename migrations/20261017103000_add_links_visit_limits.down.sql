@@ -0,0 +1,3 @@
+ALTER TABLE links
+  DROP COLUMN max_visits,
+  DROP COLUMN remaining_visits;
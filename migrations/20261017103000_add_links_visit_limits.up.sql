@@ -0,0 +1,3 @@
+ALTER TABLE links
+  ADD COLUMN max_visits integer CHECK (max_visits > 0),
+  ADD COLUMN remaining_visits integer CHECK (remaining_visits >= 0);
@@ -0,0 +1 @@
+ALTER TABLE links ADD COLUMN deleted_at timestamptz;
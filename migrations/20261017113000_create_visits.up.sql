@@ -0,0 +1,10 @@
+CREATE TABLE visits (
+  id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  link_id uuid NOT NULL REFERENCES links (id) ON DELETE CASCADE,
+  visited_at timestamptz NOT NULL DEFAULT now(),
+  referrer text,
+  user_agent text,
+  client_ip text
+);
+
+CREATE INDEX visits_link_id_visited_at ON visits (link_id, visited_at);
@@ -0,0 +1,9 @@
+DROP INDEX links_destination_host;
+DROP INDEX links_clicks_id;
+DROP INDEX links_destination_id;
+DROP INDEX links_created_at_id;
+
+ALTER TABLE links
+  DROP COLUMN destination_host,
+  DROP COLUMN clicks,
+  DROP COLUMN created_at;
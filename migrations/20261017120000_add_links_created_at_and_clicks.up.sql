@@ -0,0 +1,13 @@
+ALTER TABLE links
+  ADD COLUMN created_at timestamptz NOT NULL DEFAULT now(),
+  ADD COLUMN clicks bigint NOT NULL DEFAULT 0,
+  ADD COLUMN destination_host text GENERATED ALWAYS AS (
+    lower(substring(destination FROM '^[A-Za-z][A-Za-z0-9+.-]*://(?:[^/?#@]*@)?(\[[^]]*\]|[^/?#:]*)'))
+  ) STORED;
+
+UPDATE links SET clicks = (SELECT count(*) FROM visits WHERE visits.link_id = links.id);
+
+CREATE INDEX links_created_at_id ON links (created_at, id);
+CREATE INDEX links_destination_id ON links (destination, id);
+CREATE INDEX links_clicks_id ON links (clicks, id);
+CREATE INDEX links_destination_host ON links (destination_host);
@@ -0,0 +1 @@
+DROP INDEX links_destination_trgm;
@@ -0,0 +1,3 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX links_destination_trgm ON links USING gin (destination gin_trgm_ops);
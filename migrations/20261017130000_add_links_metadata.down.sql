@@ -0,0 +1,7 @@
+DROP INDEX links_metadata_trgm;
+DROP INDEX links_tags;
+
+ALTER TABLE links
+  DROP COLUMN tags,
+  DROP COLUMN description,
+  DROP COLUMN title;
@@ -0,0 +1,8 @@
+ALTER TABLE links
+  ADD COLUMN title text,
+  ADD COLUMN description text,
+  ADD COLUMN tags text[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX links_tags ON links USING gin (tags);
+CREATE INDEX links_metadata_trgm ON links
+  USING gin ((coalesce(title, '') || ' ' || coalesce(description, '')) gin_trgm_ops);
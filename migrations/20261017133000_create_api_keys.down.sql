@@ -0,0 +1,3 @@
+ALTER TABLE links DROP COLUMN created_by;
+
+DROP TABLE api_keys;
@@ -0,0 +1,13 @@
+CREATE TABLE api_keys (
+  id uuid DEFAULT uuid_generate_v4 () PRIMARY KEY,
+  name text NOT NULL,
+  prefix text NOT NULL,
+  key_hash text NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  last_used_at timestamptz,
+  revoked_at timestamptz
+);
+
+CREATE UNIQUE INDEX api_keys_key_hash ON api_keys (key_hash);
+
+ALTER TABLE links ADD COLUMN created_by uuid REFERENCES api_keys (id) ON DELETE SET NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE api_keys DROP COLUMN role;
+
+DROP TYPE api_key_role;
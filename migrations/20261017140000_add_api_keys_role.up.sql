@@ -0,0 +1,5 @@
+CREATE TYPE api_key_role AS ENUM ('admin', 'editor', 'viewer');
+
+-- keys issued before roles existed keep the unrestricted access they had
+ALTER TABLE api_keys ADD COLUMN role api_key_role NOT NULL DEFAULT 'admin';
+ALTER TABLE api_keys ALTER COLUMN role DROP DEFAULT;
@@ -0,0 +1,4 @@
+-- the `sub` claim of the JWT which created a link, for callers without an API key
+ALTER TABLE links ADD COLUMN created_by_subject text;
+
+CREATE INDEX links_created_by_subject ON links (created_by_subject);
@@ -0,0 +1,6 @@
+-- the destination exactly as it was submitted, before canonicalization
+ALTER TABLE links ADD COLUMN original_destination text;
+
+UPDATE links SET original_destination = destination;
+
+ALTER TABLE links ALTER COLUMN original_destination SET NOT NULL;
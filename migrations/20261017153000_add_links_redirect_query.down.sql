@@ -0,0 +1,7 @@
+ALTER TABLE links
+  DROP COLUMN forward_query,
+  DROP COLUMN utm_source,
+  DROP COLUMN utm_medium,
+  DROP COLUMN utm_campaign,
+  DROP COLUMN utm_term,
+  DROP COLUMN utm_content;
@@ -0,0 +1,9 @@
+-- whether a visit's query string is forwarded to the destination
+ALTER TABLE links ADD COLUMN forward_query boolean NOT NULL DEFAULT false;
+
+-- UTM parameters added to the destination on every visit
+ALTER TABLE links ADD COLUMN utm_source text;
+ALTER TABLE links ADD COLUMN utm_medium text;
+ALTER TABLE links ADD COLUMN utm_campaign text;
+ALTER TABLE links ADD COLUMN utm_term text;
+ALTER TABLE links ADD COLUMN utm_content text;
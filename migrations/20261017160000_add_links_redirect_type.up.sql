@@ -0,0 +1,3 @@
+-- the HTTP status code visitors are redirected with, 307 being the only one used so far
+ALTER TABLE links ADD COLUMN redirect_type smallint NOT NULL DEFAULT 307
+  CONSTRAINT links_redirect_type CHECK (redirect_type IN (301, 302, 307, 308));
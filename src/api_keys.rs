@@ -0,0 +1,185 @@
+//! Core database interactions around [`ApiKey`]s, which authenticate callers
+//! of the `/v1` API
+//!
+//! Only a SHA-256 hash of each key is stored. The key itself is shown once,
+//! when it is issued, and cannot be recovered afterwards.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+use tracing::instrument;
+use uuid::Uuid;
+
+/// Marks strings as keys for this API, e.g. for secret scanners
+pub(crate) const KEY_MARKER: &str = "are_";
+/// Number of random characters following [`KEY_MARKER`] in an issued key
+const KEY_LENGTH: usize = 40;
+/// Number of characters of an issued key stored as its `prefix`
+const PREFIX_LENGTH: usize = KEY_MARKER.len() + 6;
+
+/// What the holder of an [`ApiKey`] may do
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "api_key_role", rename_all = "lowercase")]
+pub(crate) enum Role {
+    /// Read and manage every link
+    Admin,
+    /// Create links, then read and manage only those links
+    Editor,
+    /// Read every link without changing anything
+    Viewer,
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "admin" => Ok(Self::Admin),
+            "editor" => Ok(Self::Editor),
+            "viewer" => Ok(Self::Viewer),
+            _ => anyhow::bail!("unknown role `{}`, expected admin, editor or viewer", role),
+        }
+    }
+}
+
+/// A credential for the `/v1` API, as stored in the database
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct ApiKey {
+    pub(crate) id: Uuid,
+    /// human-readable label describing who or what uses the key
+    pub(crate) name: String,
+    /// the first few characters of the key, to help recognize it
+    pub(crate) prefix: String,
+    pub(crate) role: Role,
+    pub(crate) created_at: DateTime<Utc>,
+    /// moment the key last authenticated a request, if ever
+    pub(crate) last_used_at: Option<DateTime<Utc>>,
+    /// moment the key was revoked, after which it no longer authenticates
+    pub(crate) revoked_at: Option<DateTime<Utc>>,
+}
+
+/// A newly issued [`ApiKey`] along with its secret value
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct IssuedKey {
+    pub(crate) key: ApiKey,
+    /// the full key to send as a `Bearer` token, which is never stored
+    pub(crate) secret: String,
+}
+
+/// Hashes a key for storage and lookup
+fn hash_key(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+impl ApiKey {
+    /// Generates and stores a new key labelled `name`
+    #[instrument(skip(conn))]
+    // the cast tells `sqlx::query!` how to encode the custom `api_key_role` type
+    #[allow(trivial_casts)]
+    pub(crate) async fn issue(
+        conn: &mut PgConnection,
+        name: &str,
+        role: Role,
+    ) -> sqlx::Result<IssuedKey> {
+        let random: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(KEY_LENGTH)
+            .map(char::from)
+            .collect();
+        let secret = format!("{}{}", KEY_MARKER, random);
+
+        let key = sqlx::query_as!(
+            Self,
+            r#"INSERT INTO api_keys (name, prefix, key_hash, role) VALUES ($1, $2, $3, $4)
+            RETURNING id, name, prefix, role AS "role: Role", created_at, last_used_at,
+                revoked_at"#,
+            name,
+            &secret[..PREFIX_LENGTH],
+            hash_key(&secret),
+            role as Role
+        )
+        .fetch_one(conn)
+        .await?;
+
+        Ok(IssuedKey { key, secret })
+    }
+
+    /// Lists all keys, including revoked ones, oldest first
+    #[instrument(skip(conn))]
+    pub(crate) async fn list(conn: &mut PgConnection) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"SELECT id, name, prefix, role AS "role: Role", created_at, last_used_at, revoked_at
+            FROM api_keys ORDER BY created_at, id"#
+        )
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Revokes the key with a given `id`, returning whether an active key was revoked
+    #[instrument(skip(conn))]
+    pub(crate) async fn revoke(conn: &mut PgConnection, id: Uuid) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE api_keys SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL",
+            id
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Finds the active key matching `secret`, recording that it was used
+    #[instrument(skip(conn, secret))]
+    pub(crate) async fn authenticate(
+        conn: &mut PgConnection,
+        secret: &str,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"UPDATE api_keys SET last_used_at = now()
+            WHERE key_hash = $1 AND revoked_at IS NULL
+            RETURNING id, name, prefix, role AS "role: Role", created_at, last_used_at,
+                revoked_at"#,
+            hash_key(secret)
+        )
+        .fetch_optional(conn)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::test_db;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_issue_and_revoke() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let issued = ApiKey::issue(&mut conn, "ci", Role::Viewer).await?;
+        assert!(issued.secret.starts_with(&issued.key.prefix));
+        assert_eq!(issued.secret.len(), KEY_MARKER.len() + KEY_LENGTH);
+
+        let authenticated = ApiKey::authenticate(&mut conn, &issued.secret)
+            .await?
+            .expect("key should authenticate");
+        assert_eq!(authenticated.id, issued.key.id);
+        assert_eq!(authenticated.role, Role::Viewer);
+        assert!(authenticated.last_used_at.is_some());
+        assert_eq!(ApiKey::authenticate(&mut conn, "are_wrong").await?, None);
+
+        assert!(ApiKey::revoke(&mut conn, issued.key.id).await?);
+        assert!(!ApiKey::revoke(&mut conn, issued.key.id).await?);
+        assert_eq!(ApiKey::authenticate(&mut conn, &issued.secret).await?, None);
+        assert!(ApiKey::list(&mut conn).await?[0].revoked_at.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+//! One-off administrative tasks, run from the command line instead of serving HTTP
+
+use anyhow::{bail, Result};
+use chrono::{Duration, Utc};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    api_keys::{ApiKey, Role},
+    config::AppConfig,
+    db,
+    links::Link,
+};
+
+/// Permanently removes soft-deleted [`Link`]s once they have outlived the
+/// configured `links.retention_days`
+pub async fn purge(config: &AppConfig) -> Result<()> {
+    let pool = db::new_pool(config).await?;
+    let mut conn = pool.acquire().await?;
+
+    let cutoff = Utc::now() - Duration::days(config.links.retention_days.into());
+    let purged = Link::purge_deleted(&mut conn, cutoff).await?;
+
+    info!(purged, %cutoff, "purged soft-deleted links");
+    Ok(())
+}
+
+/// Manages the [`ApiKey`]s which authenticate callers of the `/v1` API
+///
+/// Accepts `issue <name> [admin|editor|viewer]`, `list` or `revoke <id>` as
+/// `args`. Keys are issued as editors unless another role is given. An issued
+/// key is printed once and cannot be shown again.
+pub async fn keys(config: &AppConfig, args: &[String]) -> Result<()> {
+    let pool = db::new_pool(config).await?;
+    let mut conn = pool.acquire().await?;
+
+    match args {
+        [command, name, rest @ ..] if command == "issue" && rest.len() <= 1 => {
+            let role = rest.first().map_or(Ok(Role::Editor), |role| role.parse())?;
+            let issued = ApiKey::issue(&mut conn, name, role).await?;
+            info!(id = %issued.key.id, name = %issued.key.name, ?role, "issued API key");
+            println!("{}", issued.secret);
+        }
+        [command] if command == "list" => {
+            for key in ApiKey::list(&mut conn).await? {
+                let status = match (key.revoked_at, key.last_used_at) {
+                    (Some(revoked_at), _) => format!("revoked {}", revoked_at),
+                    (None, Some(last_used_at)) => format!("last used {}", last_used_at),
+                    (None, None) => "never used".to_owned(),
+                };
+                println!(
+                    "{}\t{}…\t{}\t{:?}\t{}",
+                    key.id, key.prefix, key.name, key.role, status
+                );
+            }
+        }
+        [command, id] if command == "revoke" => {
+            let id: Uuid = id.parse()?;
+            if !ApiKey::revoke(&mut conn, id).await? {
+                bail!("no active API key with id {}", id);
+            }
+            info!(%id, "revoked API key");
+        }
+        _ => bail!("expected `keys issue <name> [role]`, `keys list` or `keys revoke <id>`"),
+    }
+
+    Ok(())
+}
@@ -7,11 +7,12 @@
 //! the ability to layer per-environment configuration files in TOML format as
 //! well as just-in-time overrides via well-named environment variables.
 
-use std::net::Ipv4Addr;
+use std::{convert::TryFrom, net::Ipv4Addr, path::PathBuf};
 
 use config::{Config, ConfigError, Environment, File};
 use secrecy::Secret;
 use serde::Deserialize;
+use url::Url;
 
 /// The root configuration object, holding all available configuration details
 /// as inner public fields
@@ -21,12 +22,29 @@ pub struct AppConfig {
     /// interactions, and authentication
     #[serde(default)]
     pub database: DatabaseConfig,
+    /// Configuration pertaining specifically to which destinations links may
+    /// redirect to
+    #[serde(default)]
+    pub destinations: DestinationsConfig,
     /// Configuration pertaining specifically to the app's exposed REST API
     #[serde(default)]
     pub http: HttpConfig,
+    /// Configuration pertaining specifically to accepting JWTs issued by an
+    /// external identity provider
+    #[serde(default)]
+    pub jwt: JwtConfig,
+    /// Configuration pertaining specifically to generating shortened links
+    #[serde(default)]
+    pub links: LinksConfig,
+    /// Configuration pertaining specifically to how visitors are redirected
+    #[serde(default)]
+    pub redirects: RedirectsConfig,
     /// Configuration pertaining specifically to observability
     #[serde(default)]
     pub telemetry: TelemetryConfig,
+    /// Configuration pertaining specifically to recording visits to links
+    #[serde(default)]
+    pub visits: VisitsConfig,
 }
 
 impl AppConfig {
@@ -77,6 +95,105 @@ impl Default for DatabaseConfig {
     }
 }
 
+/// Configuration pertaining specifically to which destinations links may
+/// redirect to, see [`crate::destinations`] for details
+#[derive(Clone, Debug, Deserialize)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct DestinationsConfig {
+    /// URL schemes a destination may use, defaulting to `http` and `https`
+    #[serde(default = "default_allowed_schemes")]
+    pub allowed_schemes: Vec<String>,
+    /// A file listing the only domains destinations may point at, if any
+    #[serde(default)]
+    pub allowlist_path: Option<PathBuf>,
+    /// A file listing domains destinations may never point at, if any
+    #[serde(default)]
+    pub blocklist_path: Option<PathBuf>,
+    /// Whether destinations may use an IP address rather than a domain name,
+    /// defaulting to `false`
+    #[serde(default)]
+    pub allow_ip_literals: bool,
+    /// Whether destinations may point at `localhost`, defaulting to `false`
+    #[serde(default)]
+    pub allow_localhost: bool,
+    /// Hosts, optionally with a port, where this service's short links are
+    /// published, such as `sho.rt`
+    #[serde(default)]
+    pub public_hosts: Vec<String>,
+    /// Select a named behavior from [`SelfLinkPolicy`] for destinations on
+    /// one of the `public_hosts`
+    #[serde(default)]
+    pub self_links: SelfLinkPolicy,
+    /// The most short links a redirect may pass through before reaching a
+    /// destination elsewhere, defaulting to `3`
+    #[serde(default = "default_max_redirect_hops")]
+    pub max_redirect_hops: u32,
+    /// Whether query parameters are sorted by name, so that destinations
+    /// differing only in parameter order are the same, defaulting to `false`
+    #[serde(default)]
+    pub sort_query_params: bool,
+    /// Whether the `tracking_params` are removed from destinations,
+    /// defaulting to `false`
+    #[serde(default)]
+    pub strip_tracking_params: bool,
+    /// Names of query parameters which only track where a visitor came from,
+    /// matched case-insensitively, where a trailing `*` matches any suffix
+    #[serde(default = "default_tracking_params")]
+    pub tracking_params: Vec<String>,
+}
+
+/// Available behaviors when creating a link whose destination is one of this
+/// service's own short links
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfLinkPolicy {
+    /// Replace the destination with wherever that short link leads
+    Resolve,
+    /// Respond with an error
+    Reject,
+}
+
+impl Default for SelfLinkPolicy {
+    fn default() -> Self {
+        Self::Resolve
+    }
+}
+
+fn default_max_redirect_hops() -> u32 {
+    3
+}
+
+fn default_tracking_params() -> Vec<String> {
+    [
+        "utm_*", "fbclid", "gclid", "dclid", "msclkid", "mc_cid", "mc_eid", "_ga", "yclid",
+    ]
+    .iter()
+    .map(|&param| param.to_owned())
+    .collect()
+}
+
+fn default_allowed_schemes() -> Vec<String> {
+    vec!["http".to_owned(), "https".to_owned()]
+}
+
+impl Default for DestinationsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: default_allowed_schemes(),
+            allowlist_path: None,
+            blocklist_path: None,
+            allow_ip_literals: false,
+            allow_localhost: false,
+            public_hosts: Vec::new(),
+            self_links: SelfLinkPolicy::default(),
+            max_redirect_hops: default_max_redirect_hops(),
+            sort_query_params: false,
+            strip_tracking_params: false,
+            tracking_params: default_tracking_params(),
+        }
+    }
+}
+
 /// Configuration pertaining specifically to the app's exposed REST API
 #[derive(Clone, Copy, Debug, Deserialize)]
 pub struct HttpConfig {
@@ -86,6 +203,62 @@ pub struct HttpConfig {
     /// The default TCP port to bind the application to, defaulting to `8080`
     #[serde(default = "default_port")]
     pub listen_port: u16,
+    /// Whether to take the client IP address from the last entry of the
+    /// `X-Forwarded-For` header, which is only safe behind a single trusted
+    /// reverse proxy, defaulting to `false`
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+    /// Limits creating links, per API key or JWT subject
+    #[serde(default = "default_create_rate_limit")]
+    pub create_rate_limit: RateLimitConfig,
+    /// Limits listing, searching and exporting links, per API key or JWT subject
+    #[serde(default = "default_list_rate_limit")]
+    pub list_rate_limit: RateLimitConfig,
+    /// Limits following short links, per client IP address
+    #[serde(default = "default_redirect_rate_limit")]
+    pub redirect_rate_limit: RateLimitConfig,
+    /// Limits every request to the `/v1` API before it is authenticated, per
+    /// client IP address, which throttles guessing API keys
+    #[serde(default = "default_api_rate_limit")]
+    pub api_rate_limit: RateLimitConfig,
+}
+
+/// A token-bucket rate limit applied to each client, see [`crate::rate_limit`]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub struct RateLimitConfig {
+    /// The most requests a client may make at once
+    pub burst: u32,
+    /// The sustained number of requests a client may make per minute, where
+    /// `0` disables the limit
+    pub per_minute: u32,
+}
+
+fn default_create_rate_limit() -> RateLimitConfig {
+    RateLimitConfig {
+        burst: 20,
+        per_minute: 60,
+    }
+}
+
+fn default_list_rate_limit() -> RateLimitConfig {
+    RateLimitConfig {
+        burst: 30,
+        per_minute: 300,
+    }
+}
+
+fn default_redirect_rate_limit() -> RateLimitConfig {
+    RateLimitConfig {
+        burst: 100,
+        per_minute: 600,
+    }
+}
+
+fn default_api_rate_limit() -> RateLimitConfig {
+    RateLimitConfig {
+        burst: 60,
+        per_minute: 600,
+    }
 }
 
 fn default_listen_address() -> Ipv4Addr {
@@ -101,6 +274,379 @@ impl Default for HttpConfig {
         Self {
             listen_address: "0.0.0.0".parse().unwrap(),
             listen_port: 8080,
+            trust_forwarded_for: false,
+            create_rate_limit: default_create_rate_limit(),
+            list_rate_limit: default_list_rate_limit(),
+            redirect_rate_limit: default_redirect_rate_limit(),
+            api_rate_limit: default_api_rate_limit(),
+        }
+    }
+}
+
+/// Available strategies for generating the `hash` of a new link, see
+/// [`crate::slugs`] for details
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlugStrategy {
+    /// Random characters from `hash_alphabet`
+    Random,
+    /// A Postgres sequence encoded with `hash_alphabet`
+    Sequence,
+    /// A Postgres sequence encoded with a Hashids-style salted alphabet
+    Hashids,
+    /// A human-readable adjective/noun pair such as `brave-otter`
+    WordPair,
+}
+
+impl Default for SlugStrategy {
+    fn default() -> Self {
+        Self::Random
+    }
+}
+
+/// Available behaviors when creating a link for a destination which has
+/// already been shortened
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// Respond with an existing link, when the same caller already created
+    /// one with identical settings, rather than creating another
+    Reuse,
+    /// Always create another link with a new `hash`
+    Create,
+    /// Respond with a conflict error while any live link redirects to the
+    /// destination, whoever created it
+    Reject,
+}
+
+impl Default for DuplicatePolicy {
+    fn default() -> Self {
+        Self::Reuse
+    }
+}
+
+/// Configuration pertaining specifically to generating shortened links
+#[derive(Clone, Debug, Deserialize)]
+pub struct LinksConfig {
+    /// Select a named generation strategy from [`SlugStrategy`]
+    #[serde(default)]
+    pub strategy: SlugStrategy,
+    /// Select a named behavior from [`DuplicatePolicy`] for destinations that
+    /// have already been shortened
+    #[serde(default)]
+    pub duplicates: DuplicatePolicy,
+    /// The initial number of characters in a generated `hash`, defaulting to `5`
+    #[serde(default = "default_hash_length")]
+    pub hash_length: usize,
+    /// The length a generated `hash` may automatically grow to as collisions
+    /// become more frequent, defaulting to `12`
+    #[serde(default = "default_max_hash_length")]
+    pub max_hash_length: usize,
+    /// The characters a generated `hash` is composed of, defaulting to base-62
+    #[serde(default = "default_hash_alphabet")]
+    pub hash_alphabet: String,
+    /// The fraction of generated hashes which may collide with an existing
+    /// `hash` before the length grows, defaulting to `0.1`
+    #[serde(default = "default_collision_threshold")]
+    pub collision_threshold: f64,
+    /// The number of generated hashes the collision rate is measured over,
+    /// defaulting to `100`
+    #[serde(default = "default_collision_window")]
+    pub collision_window: u64,
+    /// Salt used to scramble the alphabet for the `hashids` strategy
+    #[serde(default)]
+    pub hashids_salt: String,
+    /// Substrings which a generated `hash` may never contain, compared
+    /// case-insensitively
+    #[serde(default)]
+    pub blocklist: Vec<String>,
+    /// The number of days soft-deleted links are kept, and may be restored,
+    /// before being permanently removed by the `purge` command, defaulting to `30`
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u32,
+}
+
+fn default_retention_days() -> u32 {
+    30
+}
+
+fn default_hash_length() -> usize {
+    5
+}
+
+fn default_max_hash_length() -> usize {
+    12
+}
+
+fn default_hash_alphabet() -> String {
+    crate::slugs::DEFAULT_HASH_ALPHABET.to_owned()
+}
+
+fn default_collision_threshold() -> f64 {
+    0.1
+}
+
+fn default_collision_window() -> u64 {
+    100
+}
+
+impl Default for LinksConfig {
+    fn default() -> Self {
+        Self {
+            strategy: SlugStrategy::default(),
+            duplicates: DuplicatePolicy::default(),
+            hash_length: default_hash_length(),
+            max_hash_length: default_max_hash_length(),
+            hash_alphabet: default_hash_alphabet(),
+            collision_threshold: default_collision_threshold(),
+            collision_window: default_collision_window(),
+            hashids_salt: String::new(),
+            blocklist: Vec::new(),
+            retention_days: default_retention_days(),
+        }
+    }
+}
+
+/// Configuration pertaining specifically to how visitors are redirected
+#[derive(Clone, Debug, Deserialize)]
+pub struct RedirectsConfig {
+    /// The [`RedirectType`] of links created without one, defaulting to `307`
+    #[serde(default)]
+    pub default_type: RedirectType,
+    /// How long browsers and CDNs may cache a permanent redirect, defaulting
+    /// to `86400`
+    #[serde(default = "default_permanent_max_age_seconds")]
+    pub permanent_max_age_seconds: u32,
+    /// Select a named behavior from [`NotFoundMode`] for unknown short links
+    #[serde(default)]
+    pub not_found: NotFoundMode,
+    /// Where visitors of unknown short links are sent in `redirect` mode
+    #[serde(default)]
+    pub not_found_url: Option<Url>,
+    /// An HTML file to respond with in `template` mode, where `{slug}` is
+    /// replaced by the requested slug
+    #[serde(default)]
+    pub not_found_template: Option<PathBuf>,
+}
+
+/// Available behaviors when a visitor follows a short link which does not exist
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotFoundMode {
+    /// Respond with `404 Not Found` and a JSON body, or an HTML page for browsers
+    Status,
+    /// Redirect to the configured `not_found_url`, such as a landing page
+    Redirect,
+    /// Respond with `404 Not Found` and the configured `not_found_template`
+    Template,
+}
+
+impl Default for NotFoundMode {
+    fn default() -> Self {
+        Self::Status
+    }
+}
+
+fn default_permanent_max_age_seconds() -> u32 {
+    86_400
+}
+
+impl Default for RedirectsConfig {
+    fn default() -> Self {
+        Self {
+            default_type: RedirectType::default(),
+            permanent_max_age_seconds: default_permanent_max_age_seconds(),
+            not_found: NotFoundMode::default(),
+            not_found_url: None,
+            not_found_template: None,
+        }
+    }
+}
+
+/// Available HTTP status codes for redirecting visitors to a link's destination,
+/// written as the status code itself
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "u16")]
+pub enum RedirectType {
+    /// `301 Moved Permanently`, which clients may follow with a `GET`
+    MovedPermanently,
+    /// `302 Found`, understood by even the oldest clients
+    Found,
+    /// `307 Temporary Redirect`
+    TemporaryRedirect,
+    /// `308 Permanent Redirect`
+    PermanentRedirect,
+}
+
+impl RedirectType {
+    /// The HTTP status code of this redirect
+    #[must_use]
+    pub fn code(self) -> u16 {
+        match self {
+            Self::MovedPermanently => 301,
+            Self::Found => 302,
+            Self::TemporaryRedirect => 307,
+            Self::PermanentRedirect => 308,
+        }
+    }
+
+    /// Whether clients may remember this redirect rather than asking again
+    #[must_use]
+    pub fn is_permanent(self) -> bool {
+        matches!(self, Self::MovedPermanently | Self::PermanentRedirect)
+    }
+}
+
+impl Default for RedirectType {
+    fn default() -> Self {
+        Self::TemporaryRedirect
+    }
+}
+
+impl TryFrom<u16> for RedirectType {
+    type Error = String;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        match code {
+            301 => Ok(Self::MovedPermanently),
+            302 => Ok(Self::Found),
+            307 => Ok(Self::TemporaryRedirect),
+            308 => Ok(Self::PermanentRedirect),
+            _ => Err(format!(
+                "redirect type must be one of 301, 302, 307 or 308, not {}",
+                code
+            )),
+        }
+    }
+}
+
+/// Configuration pertaining specifically to recording visits to links
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct VisitsConfig {
+    /// Whether to discard the host-identifying portion of each visitor's IP
+    /// address before it is stored, defaulting to `true`
+    #[serde(default = "default_anonymize_ip")]
+    pub anonymize_ip: bool,
+    /// The number of visits which may wait to be written before further
+    /// visits are dropped, defaulting to `10000`
+    #[serde(default = "default_visit_buffer_size")]
+    pub buffer_size: usize,
+    /// The largest number of visits written with a single `INSERT`,
+    /// defaulting to `500`
+    #[serde(default = "default_visit_batch_size")]
+    pub batch_size: usize,
+    /// The longest a visit waits for its batch to fill before being written,
+    /// defaulting to `1000`
+    #[serde(default = "default_visit_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+}
+
+fn default_anonymize_ip() -> bool {
+    true
+}
+
+fn default_visit_buffer_size() -> usize {
+    10_000
+}
+
+fn default_visit_batch_size() -> usize {
+    500
+}
+
+fn default_visit_flush_interval_ms() -> u64 {
+    1000
+}
+
+impl Default for VisitsConfig {
+    fn default() -> Self {
+        Self {
+            anonymize_ip: default_anonymize_ip(),
+            buffer_size: default_visit_buffer_size(),
+            batch_size: default_visit_batch_size(),
+            flush_interval_ms: default_visit_flush_interval_ms(),
+        }
+    }
+}
+
+/// Configuration pertaining specifically to accepting JWTs issued by an
+/// external identity provider, as an alternative to API keys
+///
+/// JWTs are only accepted when either `jwks_path` or `jwks_url` is set, in
+/// which case `issuer` and `audience` are required too. See [`crate::jwt`]
+/// for details.
+#[derive(Clone, Debug, Deserialize)]
+pub struct JwtConfig {
+    /// A local JSON Web Key Set file holding the provider's public keys
+    #[serde(default)]
+    pub jwks_path: Option<PathBuf>,
+    /// An `https` URL serving the provider's JSON Web Key Set, such as the
+    /// `jwks_uri` of an OpenID Connect provider
+    #[serde(default)]
+    pub jwks_url: Option<Url>,
+    /// The required `iss` claim
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// The required `aud` claim
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// How often the key set is reloaded, defaulting to `300`
+    #[serde(default = "default_jwks_refresh_seconds")]
+    pub refresh_seconds: u64,
+    /// Allowed clock skew when checking `exp` and `nbf`, defaulting to `60`
+    #[serde(default = "default_jwt_leeway_seconds")]
+    pub leeway_seconds: u64,
+    /// The claim listing the caller's roles, where a dotted path such as
+    /// `realm_access.roles` names a nested claim, defaulting to `roles`
+    #[serde(default = "default_roles_claim")]
+    pub roles_claim: String,
+    /// Claim values granting the `admin` role, defaulting to `["admin"]`
+    #[serde(default = "default_admin_roles")]
+    pub admin_roles: Vec<String>,
+    /// Claim values granting the `editor` role, defaulting to `["editor"]`
+    #[serde(default = "default_editor_roles")]
+    pub editor_roles: Vec<String>,
+    /// Claim values granting the `viewer` role, defaulting to `["viewer"]`
+    #[serde(default = "default_viewer_roles")]
+    pub viewer_roles: Vec<String>,
+}
+
+fn default_jwks_refresh_seconds() -> u64 {
+    300
+}
+
+fn default_jwt_leeway_seconds() -> u64 {
+    60
+}
+
+fn default_roles_claim() -> String {
+    "roles".to_owned()
+}
+
+fn default_admin_roles() -> Vec<String> {
+    vec!["admin".to_owned()]
+}
+
+fn default_editor_roles() -> Vec<String> {
+    vec!["editor".to_owned()]
+}
+
+fn default_viewer_roles() -> Vec<String> {
+    vec!["viewer".to_owned()]
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            jwks_path: None,
+            jwks_url: None,
+            issuer: None,
+            audience: None,
+            refresh_seconds: default_jwks_refresh_seconds(),
+            leeway_seconds: default_jwt_leeway_seconds(),
+            roles_claim: default_roles_claim(),
+            admin_roles: default_admin_roles(),
+            editor_roles: default_editor_roles(),
+            viewer_roles: default_viewer_roles(),
         }
     }
 }
@@ -0,0 +1,373 @@
+//! The policy deciding which destination URLs a [`Link`] may redirect to
+//!
+//! Without a policy a shortener happily redirects to `javascript:` URLs,
+//! local files, or services on the deployment's private network. A
+//! [`DestinationPolicy`] limits destinations to a set of schemes, optionally
+//! to an allowlist of domains, never to a blocklisted domain, and by default
+//! never to an IP address or `localhost`.
+//!
+//! Destinations on one of this service's `public_hosts` refer back to this
+//! service. Depending on the [`SelfLinkPolicy`], a destination which is
+//! another short link is either replaced by wherever that link leads, or
+//! rejected, so that links never form chains or loops.
+//!
+//! Destinations are stored in a canonical form, so that equivalent URLs
+//! deduplicate to the same [`Link`]. Beyond what parsing a URL already
+//! normalizes, a policy may sort query parameters by name and strip
+//! parameters which only track where a visitor came from.
+//!
+//! Domain lists are plain text files with one domain per line, where blank
+//! lines and `#` comments are ignored. A listed domain also covers all of its
+//! subdomains.
+//!
+//! [`Link`]: crate::links::Link
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use url::{Host, Url};
+
+use crate::{
+    config::{DestinationsConfig, SelfLinkPolicy},
+    links::{param_name, NewLinkError},
+};
+
+/// How a destination refers back to this service, see [`DestinationPolicy::self_reference`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum SelfReference {
+    /// A short link, by `hash`
+    ShortLink(String),
+    /// Any other path, such as the API
+    Other,
+}
+
+/// Checks destination URLs against a [`DestinationsConfig`]
+#[derive(Clone, Debug)]
+pub(crate) struct DestinationPolicy {
+    allowed_schemes: Vec<String>,
+    /// when set, only these domains and their subdomains are allowed
+    allowlist: Option<Vec<String>>,
+    blocklist: Vec<String>,
+    allow_ip_literals: bool,
+    allow_localhost: bool,
+    /// lowercase hosts, optionally with a port, where this service is published
+    public_hosts: Vec<String>,
+    self_links: SelfLinkPolicy,
+    max_redirect_hops: u32,
+    sort_query_params: bool,
+    /// lowercase names of query parameters to strip, empty unless stripping is enabled
+    tracking_params: Vec<String>,
+}
+
+impl Default for DestinationPolicy {
+    fn default() -> Self {
+        Self::new(&DestinationsConfig::default()).expect("default policy reads no files")
+    }
+}
+
+impl DestinationPolicy {
+    /// Builds a policy, reading any configured domain list files
+    pub(crate) fn new(config: &DestinationsConfig) -> Result<Self> {
+        Ok(Self {
+            allowed_schemes: config
+                .allowed_schemes
+                .iter()
+                .map(|scheme| scheme.to_lowercase())
+                .collect(),
+            allowlist: config
+                .allowlist_path
+                .as_deref()
+                .map(read_domains)
+                .transpose()?,
+            blocklist: config
+                .blocklist_path
+                .as_deref()
+                .map(read_domains)
+                .transpose()?
+                .unwrap_or_default(),
+            allow_ip_literals: config.allow_ip_literals,
+            allow_localhost: config.allow_localhost,
+            public_hosts: config
+                .public_hosts
+                .iter()
+                .map(|host| host.trim_end_matches('/').to_lowercase())
+                .collect(),
+            self_links: config.self_links,
+            max_redirect_hops: config.max_redirect_hops,
+            sort_query_params: config.sort_query_params,
+            tracking_params: if config.strip_tracking_params {
+                config
+                    .tracking_params
+                    .iter()
+                    .map(|param| param.to_lowercase())
+                    .collect()
+            } else {
+                Vec::new()
+            },
+        })
+    }
+
+    pub(crate) fn self_links(&self) -> SelfLinkPolicy {
+        self.self_links
+    }
+
+    pub(crate) fn max_redirect_hops(&self) -> u32 {
+        self.max_redirect_hops
+    }
+
+    /// How `url` refers back to this service, if it is on one of the `public_hosts`
+    pub(crate) fn self_reference(&self, url: &Url) -> Option<SelfReference> {
+        let host = url.host_str()?.trim_end_matches('.').to_lowercase();
+        let with_port = url
+            .port_or_known_default()
+            .map(|port| format!("{}:{}", host, port));
+        let public = self
+            .public_hosts
+            .iter()
+            .any(|public| *public == host || Some(public) == with_port.as_ref());
+        if !public {
+            return None;
+        }
+
+        let segments: Vec<_> = url
+            .path_segments()
+            .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+            .unwrap_or_default();
+        match segments.as_slice() {
+            [hash] => Some(SelfReference::ShortLink((*hash).to_owned())),
+            _ => Some(SelfReference::Other),
+        }
+    }
+
+    /// Removes tracking parameters from the query of `url` and sorts the rest,
+    /// as configured
+    ///
+    /// Parameters are matched and sorted by their decoded names, but keep
+    /// their original encoding, and parameters sharing a name keep their
+    /// relative order.
+    pub(crate) fn canonicalize(&self, url: &mut Url) {
+        let query = match url.query() {
+            Some(query) => query.to_owned(),
+            None => return,
+        };
+
+        let mut params: Vec<&str> = query
+            .split('&')
+            .filter(|param| !param.is_empty() && !self.is_tracking(&param_name(param)))
+            .collect();
+        if self.sort_query_params {
+            params.sort_by(|a, b| param_name(a).cmp(&param_name(b)));
+        }
+
+        let query = params.join("&");
+        url.set_query(Some(query.as_str()).filter(|query| !query.is_empty()));
+    }
+
+    fn is_tracking(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.tracking_params
+            .iter()
+            .any(|param| match param.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == *param,
+            })
+    }
+
+    /// Checks that a `Link` may redirect to `url`
+    pub(crate) fn check(&self, url: &Url) -> Result<(), NewLinkError> {
+        if !self
+            .allowed_schemes
+            .iter()
+            .any(|scheme| scheme == url.scheme())
+        {
+            return Err(NewLinkError::DisallowedScheme(url.scheme().to_owned()));
+        }
+
+        let domain = match url.host() {
+            // only schemes without an authority, such as `mailto:`, get here
+            None => return Ok(()),
+            Some(Host::Ipv4(_) | Host::Ipv6(_)) if self.allow_ip_literals => return Ok(()),
+            Some(Host::Ipv4(_) | Host::Ipv6(_)) => return Err(NewLinkError::IpLiteral),
+            Some(Host::Domain(domain)) => domain.trim_end_matches('.').to_lowercase(),
+        };
+
+        if !self.allow_localhost && covers(&["localhost".to_owned()], &domain) {
+            return Err(NewLinkError::Localhost);
+        }
+        if covers(&self.blocklist, &domain) {
+            return Err(NewLinkError::BlockedDomain(domain));
+        }
+        match &self.allowlist {
+            Some(allowlist) if !covers(allowlist, &domain) => {
+                Err(NewLinkError::DomainNotAllowed(domain))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether `domain` is one of `domains` or a subdomain of one
+fn covers(domains: &[String], domain: &str) -> bool {
+    domains.iter().any(|listed| {
+        domain == listed
+            || domain
+                .strip_suffix(listed.as_str())
+                .map_or(false, |subdomain| subdomain.ends_with('.'))
+    })
+}
+
+/// Reads a domain list file, see the [module docs](self) for its format
+fn read_domains(path: &Path) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("could not read domain list {}", path.display()))?;
+
+    Ok(parse_domains(&contents))
+}
+
+fn parse_domains(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|domain| domain.trim_end_matches('.').to_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(policy: &DestinationPolicy, url: &str) -> Result<(), NewLinkError> {
+        policy.check(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = DestinationPolicy::default();
+
+        assert!(check(&policy, "https://www.example.com/a?b=c").is_ok());
+        for url in [
+            "javascript:alert(1)",
+            "data:text/html,hi",
+            "file:///etc/passwd",
+        ] {
+            assert!(matches!(
+                check(&policy, url),
+                Err(NewLinkError::DisallowedScheme(_))
+            ));
+        }
+        for url in [
+            "http://10.0.0.1/",
+            "http://[::1]:8080/",
+            "http://2130706433/",
+        ] {
+            assert!(matches!(check(&policy, url), Err(NewLinkError::IpLiteral)));
+        }
+        for url in [
+            "http://localhost/",
+            "http://LOCALHOST./",
+            "http://api.localhost/",
+        ] {
+            assert!(matches!(check(&policy, url), Err(NewLinkError::Localhost)));
+        }
+        assert!(check(&policy, "http://notlocalhost.com/").is_ok());
+    }
+
+    #[test]
+    fn test_self_reference() {
+        let policy = DestinationPolicy {
+            public_hosts: vec!["sho.rt".to_owned(), "localhost:8080".to_owned()],
+            ..DestinationPolicy::default()
+        };
+        let self_reference = |url| policy.self_reference(&Url::parse(url).unwrap());
+
+        assert_eq!(
+            self_reference("https://SHO.RT/abcde?utm_source=x"),
+            Some(SelfReference::ShortLink("abcde".to_owned()))
+        );
+        assert_eq!(
+            self_reference("http://localhost:8080/abcde/"),
+            Some(SelfReference::ShortLink("abcde".to_owned()))
+        );
+        assert_eq!(
+            self_reference("https://sho.rt/v1/links"),
+            Some(SelfReference::Other)
+        );
+        assert_eq!(
+            self_reference("https://sho.rt/"),
+            Some(SelfReference::Other)
+        );
+        assert_eq!(self_reference("http://localhost:9090/abcde"), None);
+        assert_eq!(self_reference("https://www.sho.rt/abcde"), None);
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let canonicalize = |policy: &DestinationPolicy, url| {
+            let mut url = Url::parse(url).unwrap();
+            policy.canonicalize(&mut url);
+            url.to_string()
+        };
+
+        let default = DestinationPolicy::default();
+        assert_eq!(
+            canonicalize(&default, "https://example.com/?b=2&&a=1&utm_source=x"),
+            "https://example.com/?b=2&a=1&utm_source=x"
+        );
+
+        let policy = DestinationPolicy::new(&DestinationsConfig {
+            sort_query_params: true,
+            strip_tracking_params: true,
+            ..DestinationsConfig::default()
+        })
+        .unwrap();
+        assert_eq!(
+            canonicalize(
+                &policy,
+                "https://example.com/?q=b%26c&UTM_Source=x&a=2&fbclid=y&a=1#top"
+            ),
+            "https://example.com/?a=2&a=1&q=b%26c#top"
+        );
+        assert_eq!(
+            canonicalize(&policy, "https://example.com/?utm_medium=email&gclid=z"),
+            "https://example.com/"
+        );
+        assert_eq!(
+            canonicalize(&policy, "https://example.com/?utmost=1"),
+            "https://example.com/?utmost=1"
+        );
+        assert_eq!(
+            canonicalize(
+                &policy,
+                "https://example.com/?utm%5Fsource=x&b=1&%61=2&fbcl%69d=y"
+            ),
+            "https://example.com/?%61=2&b=1"
+        );
+    }
+
+    #[test]
+    fn test_domain_lists() {
+        let domains = parse_domains("# partners\nExample.com\n\n  shop.example.org. # sale\n");
+        assert_eq!(domains, vec!["example.com", "shop.example.org"]);
+
+        let policy = DestinationPolicy {
+            allowlist: Some(domains),
+            blocklist: vec!["ads.example.com".to_owned()],
+            ..DestinationPolicy::default()
+        };
+        assert!(check(&policy, "https://example.com/").is_ok());
+        assert!(check(&policy, "https://www.example.com/").is_ok());
+        assert!(matches!(
+            check(&policy, "https://badexample.com/"),
+            Err(NewLinkError::DomainNotAllowed(domain)) if domain == "badexample.com"
+        ));
+        assert!(matches!(
+            check(&policy, "https://example.org/"),
+            Err(NewLinkError::DomainNotAllowed(_))
+        ));
+        assert!(matches!(
+            check(&policy, "https://tracker.ads.example.com/"),
+            Err(NewLinkError::BlockedDomain(_))
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+//! Serialization of [`Link`]s for bulk export, one row at a time so that
+//! exports can be streamed without holding every `Link` in memory
+
+use serde::{ser::SerializeMap, Deserialize, Serializer};
+use serde_json::Value;
+
+use crate::links::Link;
+
+/// Available formats for `GET /v1/links/export`
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportFormat {
+    /// One JSON object per line
+    Ndjson,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+impl Default for ExportFormat {
+    fn default() -> Self {
+        Self::Ndjson
+    }
+}
+
+/// Fields of an exported [`Link`], in order, shared by every [`ExportFormat`]
+/// so that they describe the same data
+///
+/// Each name is a field of `Link`'s serialized form, which provides its value.
+const FIELDS: &[&str] = &[
+    "id",
+    "hash",
+    "destination",
+    "original_destination",
+    "created_at",
+    "expires_at",
+    "max_visits",
+    "remaining_visits",
+    "clicks",
+    "title",
+    "description",
+    "tags",
+    "created_by",
+    "created_by_subject",
+    "forward_query",
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content",
+    "redirect_type",
+];
+
+impl ExportFormat {
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Self::Ndjson => "application/x-ndjson",
+            Self::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub(crate) fn file_name(self) -> &'static str {
+        match self {
+            Self::Ndjson => "links.ndjson",
+            Self::Csv => "links.csv",
+        }
+    }
+
+    /// Appends anything which precedes the first row to `buf`
+    pub(crate) fn write_header(self, buf: &mut Vec<u8>) {
+        if self == Self::Csv {
+            buf.extend_from_slice(FIELDS.join(",").as_bytes());
+            buf.extend_from_slice(b"\r\n");
+        }
+    }
+
+    /// Appends a single `link`, including its line terminator, to `buf`
+    pub(crate) fn write_row(self, buf: &mut Vec<u8>, link: &Link) -> serde_json::Result<()> {
+        let values = field_values(link)?;
+
+        match self {
+            Self::Ndjson => {
+                let mut serializer = serde_json::Serializer::new(&mut *buf);
+                let mut object = serializer.serialize_map(Some(FIELDS.len()))?;
+                for (name, value) in FIELDS.iter().zip(&values) {
+                    object.serialize_entry(name, value)?;
+                }
+                object.end()?;
+                buf.push(b'\n');
+            }
+            Self::Csv => {
+                let row: Vec<_> = values
+                    .iter()
+                    .map(|value| csv_field(&csv_value(value)))
+                    .collect();
+                buf.extend_from_slice(row.join(",").as_bytes());
+                buf.extend_from_slice(b"\r\n");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The value of each of [`FIELDS`] for `link`, in order
+fn field_values(link: &Link) -> serde_json::Result<Vec<Value>> {
+    let mut link = serde_json::to_value(link)?;
+    Ok(FIELDS.iter().map(|name| link[*name].take()).collect())
+}
+
+/// Renders a field's value as the unquoted contents of a CSV field, leaving
+/// missing values empty and joining lists with commas
+fn csv_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(value) => value.clone(),
+        Value::Array(values) => values.iter().map(csv_value).collect::<Vec<_>>().join(","),
+        value => value.to_string(),
+    }
+}
+
+/// Quotes a CSV field per RFC 4180 when it contains a delimiter, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| matches!(c, ',' | '"' | '\r' | '\n')) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use url::Url;
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn test_write_row() -> Result<()> {
+        let mut link = Link::new(&Url::parse("https://www.google.com/search?q=a,b")?);
+        link.hash = "abcde".to_owned();
+        link.original_destination = "HTTPS://www.Google.com/search?q=a,b".to_owned();
+        link.max_visits = Some(3);
+        link.tags = vec!["sale".to_owned(), "spring".to_owned()];
+        let created_at = serde_json::to_value(link.created_at)?;
+        let created_at = created_at.as_str().unwrap_or_default();
+
+        let mut csv = Vec::new();
+        ExportFormat::Csv.write_header(&mut csv);
+        ExportFormat::Csv.write_row(&mut csv, &link)?;
+        let csv = String::from_utf8(csv)?;
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], FIELDS.join(","));
+        assert_eq!(
+            lines[1],
+            format!(
+                "{},abcde,\"https://www.google.com/search?q=a,b\",\"HTTPS://www.Google.com/search?q=a,b\",{},,3,,0,,,\"sale,spring\",,,false,,,,,,{}",
+                link.id, created_at, link.redirect_type
+            )
+        );
+
+        let mut ndjson = Vec::new();
+        ExportFormat::Ndjson.write_header(&mut ndjson);
+        ExportFormat::Ndjson.write_row(&mut ndjson, &link)?;
+        assert_eq!(ndjson.pop(), Some(b'\n'));
+        let object: serde_json::Map<String, Value> = serde_json::from_slice(&ndjson)?;
+        assert_eq!(object.len(), FIELDS.len());
+        let serialized = serde_json::to_value(&link)?;
+        for name in FIELDS {
+            assert!(object.contains_key(*name), "{}", name);
+            assert_eq!(object[*name], serialized[*name], "{}", name);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+//! Validation of JWTs issued by an external identity provider, such as a
+//! company's single sign-on, as an alternative to [`ApiKey`]s
+//!
+//! Tokens are verified against the provider's public keys, published as a
+//! JSON Web Key Set (JWKS) in a local file or at a URL. The key set is
+//! reloaded every `refresh_seconds`, so rotated keys are picked up without a
+//! restart, while a failed reload keeps the previous keys.
+//!
+//! [`ApiKey`]: crate::api_keys::ApiKey
+
+use std::{
+    convert::TryFrom,
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use hyper::{body, Body, Client, Uri};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+use tracing::{info, instrument, warn};
+use url::Url;
+
+use crate::{api_keys::Role, config::JwtConfig};
+
+/// The longest a request for a remote key set may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a key set is loaded from
+#[derive(Clone, Debug)]
+enum JwksSource {
+    Path(PathBuf),
+    Url(Url),
+}
+
+impl fmt::Display for JwksSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path(path) => path.display().fmt(f),
+            Self::Url(url) => url.fmt(f),
+        }
+    }
+}
+
+impl JwksSource {
+    async fn load(&self) -> anyhow::Result<Vec<VerificationKey>> {
+        let json = match self {
+            Self::Path(path) => tokio::fs::read(path)
+                .await
+                .with_context(|| format!("could not read {}", path.display()))?,
+            Self::Url(url) => fetch(url).await?,
+        };
+        let jwks: Jwks = serde_json::from_slice(&json).context("malformed key set")?;
+
+        let keys: Vec<_> = jwks
+            .keys
+            .iter()
+            .filter(|jwk| jwk.key_use.as_deref() != Some("enc"))
+            .filter_map(|jwk| match VerificationKey::try_from(jwk) {
+                Ok(key) => Some(key),
+                Err(err) => {
+                    warn!(kid = ?jwk.kid, error = %err, "skipping unusable key");
+                    None
+                }
+            })
+            .collect();
+        if keys.is_empty() {
+            bail!("key set holds no usable signing keys");
+        }
+
+        Ok(keys)
+    }
+}
+
+/// Fetches the body of a successful `GET` request to `url`
+async fn fetch(url: &Url) -> anyhow::Result<Vec<u8>> {
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_only()
+        .enable_http1()
+        .build();
+    let client = Client::builder().build::<_, Body>(https);
+    let request: Uri = url.as_str().parse()?;
+
+    let response = tokio::time::timeout(FETCH_TIMEOUT, client.get(request))
+        .await
+        .with_context(|| format!("timed out fetching {}", url))??;
+    if !response.status().is_success() {
+        bail!("{} responded with {}", url, response.status());
+    }
+
+    Ok(body::to_bytes(response.into_body()).await?.to_vec())
+}
+
+/// A JSON Web Key Set, as defined by RFC 7517
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// The subset of a JSON Web Key needed to verify signatures
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    alg: Option<String>,
+    #[serde(default, rename = "use")]
+    key_use: Option<String>,
+    #[serde(default)]
+    crv: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+    #[serde(default)]
+    x: Option<String>,
+    #[serde(default)]
+    y: Option<String>,
+}
+
+impl Jwk {
+    /// A parameter required by this key's type
+    fn param<'a>(&self, value: &'a Option<String>, name: &str) -> anyhow::Result<&'a str> {
+        value
+            .as_deref()
+            .with_context(|| format!("{} key is missing `{}`", self.kty, name))
+    }
+
+    /// A required base64url-encoded parameter, decoded
+    fn bytes(&self, value: &Option<String>, name: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(base64::decode_config(
+            self.param(value, name)?,
+            base64::URL_SAFE_NO_PAD,
+        )?)
+    }
+}
+
+/// A public key from a [`Jwk`], ready to verify signatures
+#[derive(Clone)]
+struct VerificationKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+impl fmt::Debug for VerificationKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VerificationKey")
+            .field("kid", &self.kid)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TryFrom<&Jwk> for VerificationKey {
+    type Error = anyhow::Error;
+
+    fn try_from(jwk: &Jwk) -> Result<Self, Self::Error> {
+        let (default_algorithm, key) = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+            ("RSA", _) => (
+                Algorithm::RS256,
+                DecodingKey::from_rsa_components(jwk.param(&jwk.n, "n")?, jwk.param(&jwk.e, "e")?)?,
+            ),
+            ("EC", Some(crv @ ("P-256" | "P-384"))) => {
+                // an uncompressed curve point
+                let mut point = vec![4];
+                point.extend(jwk.bytes(&jwk.x, "x")?);
+                point.extend(jwk.bytes(&jwk.y, "y")?);
+                let algorithm = if crv == "P-256" {
+                    Algorithm::ES256
+                } else {
+                    Algorithm::ES384
+                };
+                (algorithm, DecodingKey::from_ec_der(&point))
+            }
+            ("OKP", Some("Ed25519")) => (
+                Algorithm::EdDSA,
+                DecodingKey::from_ed_der(&jwk.bytes(&jwk.x, "x")?),
+            ),
+            (kty, crv) => bail!("unsupported key type {} with curve {:?}", kty, crv),
+        };
+        let algorithm = match &jwk.alg {
+            Some(alg) => Algorithm::from_str(alg)?,
+            None => default_algorithm,
+        };
+
+        Ok(Self {
+            kid: jwk.kid.clone(),
+            algorithm,
+            key,
+        })
+    }
+}
+
+/// The claims of a validated JWT which identify its caller
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+/// The caller identified by a validated JWT
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Identity {
+    /// the token's `sub` claim
+    pub(crate) subject: String,
+    /// the most privileged [`Role`] granted by the token's roles claim, if any
+    pub(crate) role: Option<Role>,
+}
+
+/// Reasons a JWT was rejected
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum JwtError {
+    #[error("no key in the key set matches the token")]
+    UnknownKey,
+    #[error(transparent)]
+    Invalid(#[from] jsonwebtoken::errors::Error),
+}
+
+/// Validates JWTs against a periodically refreshed key set, configured by [`JwtConfig`]
+#[derive(Debug)]
+pub(crate) struct JwtValidator {
+    source: JwksSource,
+    config: JwtConfig,
+    issuer: String,
+    audience: String,
+    keys: RwLock<Vec<VerificationKey>>,
+}
+
+impl JwtValidator {
+    /// Loads the configured key set, or returns `None` when JWTs are not
+    /// accepted because no key set is configured
+    ///
+    /// Fails if the key set cannot be loaded, rather than starting with no
+    /// way to validate tokens.
+    #[instrument(skip(config))]
+    pub(crate) async fn load(config: &JwtConfig) -> anyhow::Result<Option<Self>> {
+        let source = match (&config.jwks_path, &config.jwks_url) {
+            (None, None) => return Ok(None),
+            (Some(path), None) => JwksSource::Path(path.clone()),
+            // anyone able to swap a key set fetched over plain HTTP could mint tokens
+            (None, Some(url)) if url.scheme() != "https" => {
+                bail!("jwt.jwks_url must use https, or use jwt.jwks_path for a local key set")
+            }
+            (None, Some(url)) => JwksSource::Url(url.clone()),
+            (Some(_), Some(_)) => bail!("only one of jwt.jwks_path and jwt.jwks_url may be set"),
+        };
+        let issuer = config
+            .issuer
+            .clone()
+            .context("jwt.issuer is required when accepting JWTs")?;
+        let audience = config
+            .audience
+            .clone()
+            .context("jwt.audience is required when accepting JWTs")?;
+
+        let keys = source.load().await.context("could not load JWKS")?;
+        info!(keys = keys.len(), %source, "loaded JWKS");
+
+        Ok(Some(Self {
+            source,
+            config: config.clone(),
+            issuer,
+            audience,
+            keys: RwLock::new(keys),
+        }))
+    }
+
+    /// Reloads the key set, keeping the current keys if that fails
+    #[instrument(skip(self))]
+    async fn refresh(&self) -> anyhow::Result<()> {
+        let keys = self.source.load().await?;
+        *self.keys.write().expect("JWKS lock is poisoned") = keys;
+        Ok(())
+    }
+
+    /// Spawns a task which refreshes the key set every `refresh_seconds`
+    pub(crate) fn spawn_refresh(self: Arc<Self>) -> JoinHandle<()> {
+        let period = Duration::from_secs(self.config.refresh_seconds.max(1));
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // the first tick completes immediately, and the keys are fresh
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+                if let Err(err) = self.refresh().await {
+                    warn!(error = %format!("{:#}", err), "could not refresh JWKS");
+                }
+            }
+        })
+    }
+
+    /// Verifies the signature, issuer, audience and expiry of `token`,
+    /// returning the [`Identity`] it carries
+    pub(crate) fn validate(&self, token: &str) -> Result<Identity, JwtError> {
+        let header = decode_header(token)?;
+        let key = {
+            let keys = self.keys.read().expect("JWKS lock is poisoned");
+            match &header.kid {
+                Some(kid) => keys.iter().find(|key| key.kid.as_ref() == Some(kid)),
+                // a token without `kid` is only unambiguous with a single key
+                None if keys.len() == 1 => keys.first(),
+                None => None,
+            }
+            .cloned()
+            .ok_or(JwtError::UnknownKey)?
+        };
+
+        let mut validation = Validation::new(key.algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.validate_nbf = true;
+        validation.leeway = self.config.leeway_seconds;
+
+        let claims = decode::<Claims>(token, &key.key, &validation)?.claims;
+        let role = self.role(&claims);
+
+        Ok(Identity {
+            subject: claims.sub,
+            role,
+        })
+    }
+
+    /// The most privileged [`Role`] granted by the configured roles claim
+    ///
+    /// The claim may be an array of strings or a single space-separated
+    /// string, as is common for `scope`.
+    fn role(&self, claims: &Claims) -> Option<Role> {
+        let mut path = self.config.roles_claim.split('.');
+        let first = claims.other.get(path.next()?)?;
+        let claim = path.try_fold(first, Value::get)?;
+
+        let granted: Vec<&str> = match claim {
+            Value::String(roles) => roles.split_whitespace().collect(),
+            Value::Array(roles) => roles.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        let grants = |names: &[String]| {
+            granted
+                .iter()
+                .any(|role| names.iter().any(|name| name == role))
+        };
+
+        if grants(&self.config.admin_roles) {
+            Some(Role::Admin)
+        } else if grants(&self.config.editor_roles) {
+            Some(Role::Editor)
+        } else if grants(&self.config.viewer_roles) {
+            Some(Role::Viewer)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::json;
+
+    /// A locally generated Ed25519 key pair, published under `kid`
+    struct TestKey {
+        kid: &'static str,
+        pkcs8: Vec<u8>,
+    }
+
+    impl TestKey {
+        fn generate(kid: &'static str) -> Result<Self> {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+                .map_err(|_| anyhow::anyhow!("could not generate key"))?;
+            Ok(Self {
+                kid,
+                pkcs8: pkcs8.as_ref().to_vec(),
+            })
+        }
+
+        fn jwk(&self) -> Result<Value> {
+            let pair = Ed25519KeyPair::from_pkcs8(&self.pkcs8)
+                .map_err(|_| anyhow::anyhow!("could not parse key"))?;
+            Ok(json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "use": "sig",
+                "kid": self.kid,
+                "x": base64::encode_config(pair.public_key().as_ref(), base64::URL_SAFE_NO_PAD),
+            }))
+        }
+
+        fn sign(&self, claims: &Value) -> Result<String> {
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = Some(self.kid.to_owned());
+            Ok(encode(
+                &header,
+                claims,
+                &EncodingKey::from_ed_der(&self.pkcs8),
+            )?)
+        }
+    }
+
+    fn write_jwks(path: &std::path::Path, keys: &[&TestKey]) -> Result<()> {
+        let keys = keys
+            .iter()
+            .map(|key| key.jwk())
+            .collect::<Result<Vec<_>>>()?;
+        std::fs::write(path, serde_json::to_vec(&json!({ "keys": keys }))?)?;
+        Ok(())
+    }
+
+    fn claims(roles: &Value) -> Value {
+        json!({
+            "sub": "alice@example.com",
+            "iss": "https://sso.example.com",
+            "aud": "links",
+            "exp": get_current_timestamp() + 300,
+            "realm_access": { "roles": roles },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_validate() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("jwks-{}.json", uuid::Uuid::new_v4()));
+        let (old, new) = (TestKey::generate("old")?, TestKey::generate("new")?);
+        write_jwks(&path, &[&old])?;
+
+        let config = JwtConfig {
+            jwks_path: Some(path.clone()),
+            issuer: Some("https://sso.example.com".to_owned()),
+            audience: Some("links".to_owned()),
+            roles_claim: "realm_access.roles".to_owned(),
+            ..JwtConfig::default()
+        };
+        let validator = JwtValidator::load(&config)
+            .await?
+            .expect("JWTs are configured");
+
+        let identity = validator.validate(&old.sign(&claims(&json!(["viewer", "editor"])))?)?;
+        assert_eq!(
+            identity,
+            Identity {
+                subject: "alice@example.com".to_owned(),
+                role: Some(Role::Editor),
+            }
+        );
+        assert_eq!(
+            validator
+                .validate(&old.sign(&claims(&json!("other")))?)?
+                .role,
+            None
+        );
+
+        let mut expired = claims(&json!([]));
+        expired["exp"] = json!(get_current_timestamp() - 3600);
+        let mut wrong_audience = claims(&json!([]));
+        wrong_audience["aud"] = json!("another-app");
+        let mut wrong_issuer = claims(&json!([]));
+        wrong_issuer["iss"] = json!("https://evil.example.com");
+        for claims in [expired, wrong_audience, wrong_issuer] {
+            assert!(matches!(
+                validator.validate(&old.sign(&claims)?),
+                Err(JwtError::Invalid(_))
+            ));
+        }
+
+        // a rotated key is unknown until the key set is refreshed
+        let token = new.sign(&claims(&json!(["admin"])))?;
+        assert!(matches!(
+            validator.validate(&token),
+            Err(JwtError::UnknownKey)
+        ));
+        write_jwks(&path, &[&new])?;
+        validator.refresh().await?;
+        assert_eq!(validator.validate(&token)?.role, Some(Role::Admin));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_unconfigured() -> Result<()> {
+        assert!(JwtValidator::load(&JwtConfig::default()).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_insecure_url() -> Result<()> {
+        let config = JwtConfig {
+            jwks_url: Some(Url::parse("http://sso.example.com/.well-known/jwks.json")?),
+            issuer: Some("https://sso.example.com".to_owned()),
+            audience: Some("links".to_owned()),
+            ..JwtConfig::default()
+        };
+        assert!(JwtValidator::load(&config).await.is_err());
+        Ok(())
+    }
+}
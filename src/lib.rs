@@ -25,10 +25,19 @@ A full-featured example REST service using [`axum`], [`sqlx`], [`tokio`], and
 )]
 #![allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
 
+mod api_keys;
+pub mod commands;
 pub mod config;
 pub(crate) mod db;
+mod destinations;
+mod export;
+mod jwt;
 mod links;
+mod pages;
+mod rate_limit;
 pub mod server;
+mod slugs;
 pub mod telemetry;
 #[cfg(test)]
 mod test_helpers;
+mod visits;
@@ -1,137 +1,2146 @@
 //! Core database interactions around [`Link`]s
 
-use std::convert::TryFrom;
+use std::{borrow::Cow, convert::TryFrom};
 
+use chrono::{DateTime, SubsecRound, Utc};
+use futures::stream::BoxStream;
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgConnection};
+use sqlx::{Connection, FromRow, PgConnection, Postgres, QueryBuilder};
 use tracing::instrument;
-use url::Url;
+use url::{form_urlencoded, Url};
 use uuid::Uuid;
 
+use crate::{
+    config::{DuplicatePolicy, RedirectType, SelfLinkPolicy},
+    destinations::{DestinationPolicy, SelfReference},
+    slugs::{self, Slugs, DEFAULT_HASH_ALPHABET},
+};
+
 /// An input-only type used to extract the mandatory fields for creating a new [`Link`]
-#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
 pub(crate) struct NewLink {
     /// fully resolved target URL to redirect to
     destination: String,
+    /// optional human-readable vanity slug to use in place of a generated `hash`
+    #[serde(default)]
+    slug: Option<String>,
+    /// optional moment after which the link stops redirecting
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    /// optional number of times the link may be visited before it stops redirecting
+    #[serde(default)]
+    max_visits: Option<i32>,
+    /// optional human-readable name for the link
+    #[serde(default)]
+    title: Option<String>,
+    /// optional free-form notes about the link
+    #[serde(default)]
+    description: Option<String>,
+    /// optional labels for organizing links, such as a campaign or owning team
+    #[serde(default)]
+    tags: Vec<String>,
+    /// whether the query string of a visit is forwarded to the destination
+    #[serde(default)]
+    forward_query: bool,
+    /// optional `utm_source` parameter added to the destination on every visit
+    #[serde(default)]
+    utm_source: Option<String>,
+    /// optional `utm_medium` parameter added to the destination on every visit
+    #[serde(default)]
+    utm_medium: Option<String>,
+    /// optional `utm_campaign` parameter added to the destination on every visit
+    #[serde(default)]
+    utm_campaign: Option<String>,
+    /// optional `utm_term` parameter added to the destination on every visit
+    #[serde(default)]
+    utm_term: Option<String>,
+    /// optional `utm_content` parameter added to the destination on every visit
+    #[serde(default)]
+    utm_content: Option<String>,
+    /// optional HTTP status code visitors are redirected with, defaulting to
+    /// the configured
+    /// [`default_type`](crate::config::RedirectsConfig::default_type)
+    #[serde(default)]
+    pub(crate) redirect_type: Option<RedirectType>,
+    /// the caller creating the link, which is never read from the request body
+    #[serde(skip)]
+    pub(crate) created_by: Option<Owner>,
+    /// only resolve a destination through links created by this caller, which
+    /// is never read from the request body
+    #[serde(skip)]
+    pub(crate) visible_to: Option<Owner>,
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, FromRow, Serialize)]
 /// A shortened URL that redirects to a full URL
 pub(crate) struct Link {
-    id: Uuid,
+    pub(crate) id: Uuid,
     /// The short, opaque segment exposed as the path portion of URLs shortened by this app
-    hash: String,
+    pub(crate) hash: String,
     /// fully resolved target URL to redirect to, has been previously parsed as a [`Url`] prior to insertion
     pub(crate) destination: String,
+    /// the destination exactly as it was submitted, before it was canonicalized
+    pub(crate) original_destination: String,
+    /// moment after which the link stops redirecting, if any
+    pub(crate) expires_at: Option<DateTime<Utc>>,
+    /// number of times the link may be visited in total, if limited
+    pub(crate) max_visits: Option<i32>,
+    /// number of visits left before the link stops redirecting, if limited
+    pub(crate) remaining_visits: Option<i32>,
+    /// moment the link was soft-deleted, after which it stops redirecting until restored
+    pub(crate) deleted_at: Option<DateTime<Utc>>,
+    /// moment the link was created
+    pub(crate) created_at: DateTime<Utc>,
+    /// number of recorded visits, updated as visits are flushed to the database
+    pub(crate) clicks: i64,
+    /// human-readable name for the link, if any
+    pub(crate) title: Option<String>,
+    /// free-form notes about the link, if any
+    pub(crate) description: Option<String>,
+    /// labels for organizing links, lowercase and sorted
+    pub(crate) tags: Vec<String>,
+    /// id of the API key which created the link, if known
+    pub(crate) created_by: Option<Uuid>,
+    /// `sub` claim of the JWT which created the link, if known
+    pub(crate) created_by_subject: Option<String>,
+    /// whether the query string of a visit is forwarded to the destination
+    pub(crate) forward_query: bool,
+    /// `utm_source` parameter added to the destination on every visit, if any
+    pub(crate) utm_source: Option<String>,
+    /// `utm_medium` parameter added to the destination on every visit, if any
+    pub(crate) utm_medium: Option<String>,
+    /// `utm_campaign` parameter added to the destination on every visit, if any
+    pub(crate) utm_campaign: Option<String>,
+    /// `utm_term` parameter added to the destination on every visit, if any
+    pub(crate) utm_term: Option<String>,
+    /// `utm_content` parameter added to the destination on every visit, if any
+    pub(crate) utm_content: Option<String>,
+    /// HTTP status code visitors are redirected with, see [`RedirectType`]
+    pub(crate) redirect_type: i16,
+}
+
+/// Who created a [`Link`], which limits the `Link`s an editor may see and manage
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Owner {
+    /// An API key, by id
+    ApiKey(Uuid),
+    /// The `sub` claim of a JWT
+    Subject(String),
+}
+
+impl Owner {
+    fn api_key(&self) -> Option<Uuid> {
+        match self {
+            Self::ApiKey(id) => Some(*id),
+            Self::Subject(_) => None,
+        }
+    }
+
+    fn subject(&self) -> Option<&str> {
+        match self {
+            Self::ApiKey(_) => None,
+            Self::Subject(subject) => Some(subject),
+        }
+    }
+
+    /// Appends a condition matching only `Link`s created by this owner
+    fn push_condition(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        match self {
+            Self::ApiKey(id) => builder.push(" AND created_by = ").push_bind(*id),
+            Self::Subject(subject) => builder
+                .push(" AND created_by_subject = ")
+                .push_bind(subject.clone()),
+        };
+    }
+}
+
+/// An input-only type used to extract the fields that may be changed on an existing [`Link`]
+///
+/// Omitted fields are left unchanged.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub(crate) struct LinkUpdate {
+    /// fully resolved target URL to redirect to
+    #[serde(default)]
+    destination: Option<String>,
+    /// human-readable name for the link, cleared by an empty string
+    #[serde(default)]
+    title: Option<String>,
+    /// free-form notes about the link, cleared by an empty string
+    #[serde(default)]
+    description: Option<String>,
+    /// labels replacing all of the link's existing tags
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+    /// whether the query string of a visit is forwarded to the destination
+    #[serde(default)]
+    forward_query: Option<bool>,
+    /// `utm_source` parameter added on every visit, cleared by an empty string
+    #[serde(default)]
+    utm_source: Option<String>,
+    /// `utm_medium` parameter added on every visit, cleared by an empty string
+    #[serde(default)]
+    utm_medium: Option<String>,
+    /// `utm_campaign` parameter added on every visit, cleared by an empty string
+    #[serde(default)]
+    utm_campaign: Option<String>,
+    /// `utm_term` parameter added on every visit, cleared by an empty string
+    #[serde(default)]
+    utm_term: Option<String>,
+    /// `utm_content` parameter added on every visit, cleared by an empty string
+    #[serde(default)]
+    utm_content: Option<String>,
+    /// HTTP status code visitors are redirected with
+    #[serde(default)]
+    redirect_type: Option<RedirectType>,
+    /// only resolve a destination through links created by this caller, which
+    /// is never read from the request body
+    #[serde(skip)]
+    pub(crate) visible_to: Option<Owner>,
+}
+
+/// The outcome of [`Link::create`]
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Created {
+    /// A new `Link` was inserted
+    Inserted(Link),
+    /// An equivalent `Link` already existed and was reused
+    Existing(Link),
+}
+
+/// Response body describing whether a vanity slug could be used for a new [`Link`]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct SlugAvailability {
+    slug: String,
+    available: bool,
+    /// explanation for why the slug cannot be used, when it is not merely taken
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+/// Response body describing where a [`Link`] leads, for visitors checking
+/// before they follow it
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct LinkPreview {
+    pub(crate) hash: String,
+    /// withheld for a `Link` with limited visits, which would otherwise reveal
+    /// where it leads without using one up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) destination: Option<String>,
+    pub(crate) title: Option<String>,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) expires_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, thiserror::Error, Serialize)]
 pub(crate) enum NewLinkError {
     #[error("malformed url")]
     InvalidUrl,
+    #[error("destinations may not use the `{0}` scheme")]
+    DisallowedScheme(String),
+    #[error("destinations must use a domain name rather than an IP address")]
+    IpLiteral,
+    #[error("destinations may not point at localhost")]
+    Localhost,
+    #[error("destinations may not point at `{0}`, which is blocked")]
+    BlockedDomain(String),
+    #[error("destinations may not point at `{0}`, which is not an allowed domain")]
+    DomainNotAllowed(String),
+    #[error("destinations may not point at this service, except at an existing short link")]
+    SelfReference,
+    #[error("destination redirects through too many short links")]
+    RedirectLoop,
+    #[error("destinations may not point at a short link which expires or has limited visits")]
+    LimitedSelfReference,
+    #[error(
+        "slug must be {}-{} characters of letters, digits, `-` or `_`, and not a reserved path",
+        MIN_SLUG_LENGTH,
+        MAX_SLUG_LENGTH
+    )]
+    InvalidSlug,
+    #[error("expires_at must be in the future")]
+    InvalidExpiry,
+    #[error("max_visits must be at least 1")]
+    InvalidMaxVisits,
+    #[error(
+        "tags must be 1-{} characters without whitespace or commas, with at most {} per link",
+        MAX_TAG_LENGTH,
+        MAX_TAGS
+    )]
+    InvalidTags,
+    #[error("slug is already in use")]
+    SlugTaken,
+    #[error("destination has already been shortened")]
+    DestinationTaken,
+    #[error("could not generate an unused hash")]
+    HashesExhausted,
     #[error("could not insert into database")]
     DatabaseError,
 }
 
+/// Query parameters accepted by [`Link::list`], selecting which `Link`s to
+/// return and in which order
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub(crate) struct LinkQuery {
+    /// Whether to include `Link`s which have expired, defaulting to `false`
+    #[serde(default)]
+    pub(crate) include_expired: bool,
+    /// Only include `Link`s whose destination host is this domain or one of its subdomains
+    #[serde(default)]
+    pub(crate) domain: Option<String>,
+    /// Only include `Link`s with this tag
+    #[serde(default)]
+    pub(crate) tag: Option<String>,
+    /// Only include `Link`s created before this moment
+    #[serde(default)]
+    pub(crate) created_before: Option<DateTime<Utc>>,
+    /// Only include `Link`s created after this moment
+    #[serde(default)]
+    pub(crate) created_after: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub(crate) sort: LinkSort,
+    /// The largest number of `Link`s to return, defaulting to 50 and capped at 200
+    #[serde(default)]
+    pub(crate) limit: Option<i64>,
+    /// Opaque `next_cursor` of a previous [`LinkPage`], fetching the page after it
+    #[serde(default)]
+    pub(crate) cursor: Option<String>,
+    /// Only include `Link`s created by this caller, which is never read from
+    /// the query string
+    #[serde(skip)]
+    pub(crate) created_by: Option<Owner>,
+}
+
+impl LinkQuery {
+    fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// Decodes the `cursor`, which is only valid for the `sort` it was created with
+    fn cursor(&self) -> Result<Option<Cursor>, ListError> {
+        self.cursor
+            .as_deref()
+            .map(|cursor| {
+                Cursor::decode(cursor)
+                    .filter(|cursor| self.sort.accepts(&cursor.key))
+                    .ok_or(ListError::InvalidCursor)
+            })
+            .transpose()
+    }
+}
+
+/// Available orderings for [`Link::list`], where a leading `-` means descending
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub(crate) enum LinkSort {
+    #[serde(rename = "created_at")]
+    CreatedAt,
+    #[serde(rename = "-created_at")]
+    CreatedAtDesc,
+    #[serde(rename = "destination")]
+    Destination,
+    #[serde(rename = "-destination")]
+    DestinationDesc,
+    /// By recorded visits, which may shift `Link`s between pages as they are visited
+    #[serde(rename = "clicks")]
+    Clicks,
+    #[serde(rename = "-clicks")]
+    ClicksDesc,
+}
+
+impl Default for LinkSort {
+    fn default() -> Self {
+        Self::CreatedAtDesc
+    }
+}
+
+impl LinkSort {
+    /// The column and direction to `ORDER BY`
+    fn order(self) -> (&'static str, &'static str) {
+        match self {
+            Self::CreatedAt => ("created_at", "ASC"),
+            Self::CreatedAtDesc => ("created_at", "DESC"),
+            Self::Destination => ("destination", "ASC"),
+            Self::DestinationDesc => ("destination", "DESC"),
+            Self::Clicks => ("clicks", "ASC"),
+            Self::ClicksDesc => ("clicks", "DESC"),
+        }
+    }
+
+    /// Whether a [`Cursor`] holds a value of this ordering's column
+    fn accepts(self, key: &CursorKey) -> bool {
+        matches!(
+            (self, key),
+            (
+                Self::CreatedAt | Self::CreatedAtDesc,
+                CursorKey::CreatedAt(_)
+            ) | (
+                Self::Destination | Self::DestinationDesc,
+                CursorKey::Destination(_)
+            ) | (Self::Clicks | Self::ClicksDesc, CursorKey::Clicks(_))
+        )
+    }
+
+    /// The [`Cursor`] for the page following `link`
+    fn cursor_after(self, link: &Link) -> Cursor {
+        let key = match self {
+            Self::CreatedAt | Self::CreatedAtDesc => CursorKey::CreatedAt(link.created_at),
+            Self::Destination | Self::DestinationDesc => {
+                CursorKey::Destination(link.destination.clone())
+            }
+            Self::Clicks | Self::ClicksDesc => CursorKey::Clicks(link.clicks),
+        };
+
+        Cursor { key, id: link.id }
+    }
+}
+
+/// Position of the last `Link` on a page, handed to clients as an opaque
+/// URL-safe base64 string
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct Cursor {
+    key: CursorKey,
+    id: Uuid,
+}
+
+/// The sort column's value within a [`Cursor`]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CursorKey {
+    CreatedAt(DateTime<Utc>),
+    Destination(String),
+    Clicks(i64),
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("cursor is always serializable");
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let json = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+/// Available ways for [`Link::search`] to match a query against destinations
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SearchMode {
+    /// Destinations on exactly this host or one of its subdomains
+    Domain,
+    /// Destinations starting with the query, with or without their scheme
+    Prefix,
+    /// Destinations containing words similar to the query, tolerating typos
+    Fuzzy,
+}
+
+impl Default for SearchMode {
+    fn default() -> Self {
+        Self::Fuzzy
+    }
+}
+
+/// Query parameters accepted by [`Link::search`]
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub(crate) struct LinkSearch {
+    pub(crate) q: String,
+    #[serde(default)]
+    pub(crate) mode: SearchMode,
+    /// Only include `Link`s with this tag
+    #[serde(default)]
+    pub(crate) tag: Option<String>,
+    /// The largest number of `Link`s to return, defaulting to 50 and capped at 200
+    #[serde(default)]
+    pub(crate) limit: Option<i64>,
+    /// Only include `Link`s created by this caller, which is never read from
+    /// the query string
+    #[serde(skip)]
+    pub(crate) created_by: Option<Owner>,
+}
+
+/// Response body for [`Link::list`]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct LinkPage {
+    pub(crate) links: Vec<Link>,
+    /// `cursor` for fetching the following page, if there is one
+    pub(crate) next_cursor: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ListError {
+    #[error("invalid cursor")]
+    InvalidCursor,
+    #[error("search query must not be empty")]
+    EmptyQuery,
+    #[error("database error")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Escapes `%`, `_` and `\` so that `text` only matches itself within a `LIKE` pattern
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// The expression indexed by `links_metadata_trgm` for searching titles and descriptions
+const METADATA_TEXT: &str = "(coalesce(title, '') || ' ' || coalesce(description, ''))";
+/// Number of `Link`s returned by [`Link::list`] when no `limit` is given
+const DEFAULT_PAGE_SIZE: i64 = 50;
+/// Largest `limit` accepted by [`Link::list`]
+const MAX_PAGE_SIZE: i64 = 200;
+/// Shortest vanity slug accepted on [`NewLink`]
+const MIN_SLUG_LENGTH: usize = 3;
+/// Longest vanity slug accepted on [`NewLink`]
+const MAX_SLUG_LENGTH: usize = 64;
+/// Path segments already routed by the app, which would shadow a vanity slug:
+/// either at the top level or as a static route under `/v1/links`, where they
+/// would take precedence over managing the `Link` with that hash
+const RESERVED_SLUGS: &[&str] = &["health", "v1", "export", "search"];
+/// Longest tag accepted on a [`Link`]
+const MAX_TAG_LENGTH: usize = 64;
+/// Most tags accepted on a single [`Link`]
+const MAX_TAGS: usize = 32;
+/// Number of generated hashes tried for a single new [`Link`] before giving up
+const MAX_HASH_ATTEMPTS: usize = 10;
+/// Number of consecutive collisions for a single new [`Link`] which immediately
+/// lengthen generated hashes, regardless of the overall collision rate
+const CONSECUTIVE_COLLISIONS_BEFORE_GROWTH: usize = 3;
+
+/// Parses a user-provided destination URL into its canonical form
+///
+/// Parsing lowercases the scheme and host, converts internationalized domain
+/// names to punycode, drops default ports and resolves `.` and `..` path
+/// segments. An empty query or fragment is dropped too.
+pub(crate) fn parse_destination(destination: &str) -> Result<Url, NewLinkError> {
+    let mut url = Url::parse(destination.trim()).map_err(|_| NewLinkError::InvalidUrl)?;
+
+    if url.query() == Some("") {
+        url.set_query(None);
+    }
+    if url.fragment() == Some("") {
+        url.set_fragment(None);
+    }
+
+    Ok(url)
+}
+
+/// Validates user-provided tags, returning them lowercased, sorted and without duplicates
+pub(crate) fn normalize_tags(tags: &[String]) -> Result<Vec<String>, NewLinkError> {
+    let mut tags: Vec<_> = tags.iter().map(|tag| tag.trim().to_lowercase()).collect();
+    tags.sort_unstable();
+    tags.dedup();
+
+    let valid = tags.len() <= MAX_TAGS
+        && tags.iter().all(|tag| {
+            (1..=MAX_TAG_LENGTH).contains(&tag.chars().count())
+                && !tag.contains(|c: char| c.is_whitespace() || c == ',')
+        });
+
+    if valid {
+        Ok(tags)
+    } else {
+        Err(NewLinkError::InvalidTags)
+    }
+}
+
+/// The column value of a [`RedirectType`] status code
+fn redirect_code(code: u16) -> i16 {
+    i16::try_from(code).expect("redirect status codes fit in a smallint")
+}
+
+/// The decoded name of a `name=value` query parameter
+pub(crate) fn param_name(param: &str) -> Cow<'_, str> {
+    form_urlencoded::parse(param.as_bytes())
+        .next()
+        .map_or(Cow::Borrowed(""), |(name, _)| name)
+}
+
+/// Whether `slug` would be shadowed by one of the app's own routes, ignoring case
+pub(crate) fn is_reserved_slug(slug: &str) -> bool {
+    RESERVED_SLUGS
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(slug))
+}
+
+/// Checks that a requested vanity slug is safe to expose as a URL path segment
+pub(crate) fn validate_slug(slug: &str) -> Result<(), NewLinkError> {
+    let valid_length = (MIN_SLUG_LENGTH..=MAX_SLUG_LENGTH).contains(&slug.len());
+    let valid_chars = slug
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if valid_length && valid_chars && !is_reserved_slug(slug) {
+        Ok(())
+    } else {
+        Err(NewLinkError::InvalidSlug)
+    }
+}
+
 impl TryFrom<NewLink> for Link {
     type Error = NewLinkError;
 
     fn try_from(link: NewLink) -> Result<Self, Self::Error> {
-        let dest = Url::parse(&link.destination).map_err(|_| NewLinkError::InvalidUrl)?;
-        Ok(Self::new(&dest))
+        let dest = parse_destination(&link.destination)?;
+        let mut new = Self::new(&dest);
+        new.original_destination = link.destination;
+
+        if let Some(slug) = link.slug {
+            validate_slug(&slug)?;
+            new.hash = slug;
+        }
+
+        if let Some(expires_at) = link.expires_at {
+            if expires_at <= Utc::now() {
+                return Err(NewLinkError::InvalidExpiry);
+            }
+            new.expires_at = Some(expires_at);
+        }
+
+        if let Some(max_visits) = link.max_visits {
+            if max_visits < 1 {
+                return Err(NewLinkError::InvalidMaxVisits);
+            }
+            new.max_visits = Some(max_visits);
+            new.remaining_visits = Some(max_visits);
+        }
+
+        new.title = link.title.filter(|title| !title.is_empty());
+        new.description = link
+            .description
+            .filter(|description| !description.is_empty());
+        new.tags = normalize_tags(&link.tags)?;
+        new.created_by = link.created_by.as_ref().and_then(Owner::api_key);
+        new.created_by_subject = link
+            .created_by
+            .as_ref()
+            .and_then(Owner::subject)
+            .map(str::to_owned);
+        new.forward_query = link.forward_query;
+        new.utm_source = link.utm_source.filter(|value| !value.is_empty());
+        new.utm_medium = link.utm_medium.filter(|value| !value.is_empty());
+        new.utm_campaign = link.utm_campaign.filter(|value| !value.is_empty());
+        new.utm_term = link.utm_term.filter(|value| !value.is_empty());
+        new.utm_content = link.utm_content.filter(|value| !value.is_empty());
+        if let Some(redirect_type) = link.redirect_type {
+            new.redirect_type = redirect_code(redirect_type.code());
+        }
+
+        Ok(new)
     }
 }
 
 impl Link {
     /// Build a new `Link` given just a `destination` URL
     pub(crate) fn new(destination: &Url) -> Self {
-        let id = Uuid::new_v4();
-        let mut hash = base_x::encode(
-            "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789",
-            &id.as_bytes()[..],
-        );
-        let _remainder = hash.split_off(5);
-
         Self {
-            id,
-            hash,
+            id: Uuid::new_v4(),
+            hash: slugs::random_hash(DEFAULT_HASH_ALPHABET.as_bytes(), 5),
             destination: destination.to_string(),
+            original_destination: destination.to_string(),
+            expires_at: None,
+            max_visits: None,
+            remaining_visits: None,
+            deleted_at: None,
+            // Postgres only stores microseconds
+            created_at: Utc::now().trunc_subsecs(6),
+            clicks: 0,
+            title: None,
+            description: None,
+            tags: Vec::new(),
+            created_by: None,
+            created_by_subject: None,
+            forward_query: false,
+            utm_source: None,
+            utm_medium: None,
+            utm_campaign: None,
+            utm_term: None,
+            utm_content: None,
+            redirect_type: redirect_code(RedirectType::default().code()),
+        }
+    }
+
+    /// The [`RedirectType`] visitors are redirected with
+    pub(crate) fn redirect_type(&self) -> RedirectType {
+        // the column only holds valid status codes
+        u16::try_from(self.redirect_type)
+            .ok()
+            .and_then(|code| RedirectType::try_from(code).ok())
+            .unwrap_or_default()
+    }
+
+    /// How many seconds browsers and CDNs may cache a redirect to this `Link`,
+    /// if at all
+    ///
+    /// Only permanent redirects are cached, for at most `max_age` seconds and
+    /// never past the moment the `Link` expires. A `Link` with limited visits
+    /// is never cached, since cached visits could not be counted.
+    pub(crate) fn cache_max_age(&self, max_age: u32) -> Option<u32> {
+        if !self.redirect_type().is_permanent() || self.max_visits.is_some() {
+            return None;
+        }
+
+        match self.expires_at {
+            Some(expires_at) => {
+                let remaining = (expires_at - Utc::now()).num_seconds().max(0);
+                Some(u32::try_from(remaining).map_or(max_age, |remaining| remaining.min(max_age)))
+            }
+            None => Some(max_age),
+        }
+    }
+
+    /// Builds the URL a visit to this `Link` redirects to, given the
+    /// `destination` it leads to and the query string of the visit
+    ///
+    /// Query parameters are merged from three sources, in order, where each
+    /// parameter replaces all parameters of the same name from the sources
+    /// before it:
+    ///
+    /// 1. the query of the `destination` itself
+    /// 2. this `Link`'s UTM parameters
+    /// 3. the query of the visit, only when `forward_query` is set
+    ///
+    /// So a single `Link` can be shared by several campaigns, which each
+    /// override some of its UTM parameters in the short URL.
+    pub(crate) fn redirect_url(&self, mut destination: Url, query: Option<&str>) -> Url {
+        let mut utm = form_urlencoded::Serializer::new(String::new());
+        for (name, value) in [
+            ("utm_source", &self.utm_source),
+            ("utm_medium", &self.utm_medium),
+            ("utm_campaign", &self.utm_campaign),
+            ("utm_term", &self.utm_term),
+            ("utm_content", &self.utm_content),
+        ] {
+            if let Some(value) = value {
+                utm.append_pair(name, value);
+            }
+        }
+        let utm = utm.finish();
+        let own = destination.query().unwrap_or_default().to_owned();
+        let forwarded = query.filter(|_| self.forward_query).unwrap_or_default();
+
+        let mut params: Vec<&str> = Vec::new();
+        for source in [own.as_str(), utm.as_str(), forwarded] {
+            let source: Vec<&str> = source
+                .split('&')
+                .filter(|param| !param.is_empty())
+                .collect();
+            params.retain(|param| {
+                !source
+                    .iter()
+                    .any(|other| param_name(other) == param_name(param))
+            });
+            params.extend(source);
+        }
+
+        let query = params.join("&");
+        destination.set_query(Some(query.as_str()).filter(|query| !query.is_empty()));
+        destination
+    }
+
+    /// The caller which created this `Link`, if known
+    pub(crate) fn owner(&self) -> Option<Owner> {
+        self.created_by
+            .map(Owner::ApiKey)
+            .or_else(|| self.created_by_subject.clone().map(Owner::Subject))
+    }
+
+    /// Whether this `Link` should no longer redirect to its `destination`,
+    /// either because it has passed `expires_at` or exhausted its visits
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= Utc::now())
+            || self.remaining_visits == Some(0)
+    }
+
+    /// Describes where this `Link` leads without following it, unless its
+    /// visits are limited
+    pub(crate) fn preview(&self) -> LinkPreview {
+        LinkPreview {
+            hash: self.hash.clone(),
+            destination: self.max_visits.is_none().then(|| self.destination.clone()),
+            title: self.title.clone(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+        }
+    }
+
+    /// Whether this `Link` has been soft-deleted
+    pub(crate) fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// Claims one of a visit-limited `Link`'s remaining visits, returning
+    /// whether one was available
+    ///
+    /// The decrement and check happen in a single conditional `UPDATE`, so
+    /// concurrent visitors can never claim more than `max_visits` in total.
+    /// `Link`s without a limit always have a visit available.
+    #[instrument(skip(conn, self), fields(hash = %self.hash))]
+    pub(crate) async fn claim_visit(&self, conn: &mut PgConnection) -> sqlx::Result<bool> {
+        if self.max_visits.is_none() {
+            return Ok(true);
+        }
+
+        let claimed = sqlx::query_scalar!(
+            r#"UPDATE links SET remaining_visits = remaining_visits - 1
+            WHERE id = $1 AND remaining_visits > 0
+            RETURNING remaining_visits"#,
+            self.id
+        )
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(claimed.is_some())
+    }
+
+    /// Claims a visit from each of `links`, such as every `Link` a redirect
+    /// passes through, returning whether all of them had a visit available
+    ///
+    /// The visits are claimed in a single transaction, so a `Link` which has
+    /// used up its visits doesn't use up any visits of the others.
+    pub(crate) async fn claim_visits(
+        conn: &mut PgConnection,
+        links: &[&Self],
+    ) -> sqlx::Result<bool> {
+        let limited: Vec<_> = links
+            .iter()
+            .filter(|link| link.max_visits.is_some())
+            .collect();
+        if limited.is_empty() {
+            return Ok(true);
+        }
+
+        let mut tx = conn.begin().await?;
+        for link in limited {
+            if !link.claim_visit(&mut tx).await? {
+                return Ok(false);
+            }
+        }
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Validates a [`NewLink`] and inserts it into the database
+    ///
+    /// Unless a vanity slug was requested, the `hash` is produced by the
+    /// configured [`Slugs`] strategy, and regenerated whenever it collides with
+    /// an existing `Link`.
+    ///
+    /// The destination must satisfy the [`DestinationPolicy`]. The
+    /// [`DuplicatePolicy`] decides what happens when the destination was
+    /// already shortened: an equivalent `Link` created by the same caller is
+    /// returned as-is, any live `Link` to it is rejected, or both are ignored
+    /// in favor of inserting another `Link`. Concurrent creations for the same
+    /// destination are serialized with a transaction-scoped advisory lock.
+    #[instrument(skip(conn, slugs))]
+    pub(crate) async fn create(
+        conn: &mut PgConnection,
+        new: NewLink,
+        slugs: &Slugs,
+        duplicates: DuplicatePolicy,
+        policy: &DestinationPolicy,
+    ) -> Result<Created, NewLinkError> {
+        let destination =
+            Self::validate_destination(conn, &new.destination, policy, new.visible_to.as_ref())
+                .await?;
+        let vanity = new.slug.is_some();
+        let mut link = Self::try_from(new)?;
+        link.destination = destination.to_string();
+
+        let mut tx = conn
+            .begin()
+            .await
+            .map_err(|_| NewLinkError::DatabaseError)?;
+
+        if duplicates != DuplicatePolicy::Create {
+            Self::lock_destination(&mut tx, &link.destination)
+                .await
+                .map_err(|_| NewLinkError::DatabaseError)?;
+        }
+        match duplicates {
+            DuplicatePolicy::Reject => {
+                if Self::destination_taken(&mut tx, &link.destination)
+                    .await
+                    .map_err(|_| NewLinkError::DatabaseError)?
+                {
+                    return Err(NewLinkError::DestinationTaken);
+                }
+            }
+            DuplicatePolicy::Reuse => {
+                if let Some(existing) = Self::find_equivalent(&mut tx, &link)
+                    .await
+                    .map_err(|_| NewLinkError::DatabaseError)?
+                {
+                    // a vanity slug is only satisfied by a `Link` with that exact slug
+                    if !vanity || existing.hash == link.hash {
+                        return Ok(Created::Existing(existing));
+                    }
+                }
+            }
+            DuplicatePolicy::Create => {}
+        }
+
+        let inserted = if vanity {
+            Self::insert(&mut tx, link).await?
+        } else {
+            Self::insert_generated(&mut tx, link, slugs).await?
+        };
+
+        tx.commit().await.map_err(|_| NewLinkError::DatabaseError)?;
+
+        Ok(Created::Inserted(inserted))
+    }
+
+    /// Inserts `link` with a `hash` produced by `slugs`, retrying with a fresh
+    /// `hash` whenever it collides with an existing `Link`
+    ///
+    /// Each attempt runs in its own transaction (or savepoint) so that a
+    /// collision doesn't abort any transaction `conn` is already in.
+    async fn insert_generated(
+        conn: &mut PgConnection,
+        mut link: Link,
+        slugs: &Slugs,
+    ) -> Result<Self, NewLinkError> {
+        let mut consecutive_collisions = 0;
+        for _ in 0..MAX_HASH_ATTEMPTS {
+            link.hash = slugs
+                .generate(conn)
+                .await
+                .map_err(|_| NewLinkError::DatabaseError)?
+                .ok_or(NewLinkError::HashesExhausted)?;
+
+            let mut attempt = conn
+                .begin()
+                .await
+                .map_err(|_| NewLinkError::DatabaseError)?;
+
+            match Self::insert(&mut attempt, link.clone()).await {
+                Ok(inserted) => {
+                    attempt
+                        .commit()
+                        .await
+                        .map_err(|_| NewLinkError::DatabaseError)?;
+                    slugs.record(false);
+                    return Ok(inserted);
+                }
+                Err(NewLinkError::SlugTaken) => {
+                    slugs.record(true);
+                    consecutive_collisions += 1;
+                    if consecutive_collisions % CONSECUTIVE_COLLISIONS_BEFORE_GROWTH == 0 {
+                        slugs.grow();
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(NewLinkError::HashesExhausted)
+    }
+
+    /// Parses a user-provided destination into its canonical form and checks
+    /// it against the [`DestinationPolicy`]
+    ///
+    /// A destination which is one of this service's own short links is
+    /// replaced by wherever that link leads, when the [`SelfLinkPolicy`]
+    /// allows it. Every `Link` passed through must have been created by
+    /// `visible_to`, when given, and must neither expire nor limit its
+    /// visits, which the replaced destination would otherwise escape.
+    async fn validate_destination(
+        conn: &mut PgConnection,
+        destination: &str,
+        policy: &DestinationPolicy,
+        visible_to: Option<&Owner>,
+    ) -> Result<Url, NewLinkError> {
+        let mut url = parse_destination(destination)?;
+
+        if policy.self_reference(&url).is_some() {
+            if policy.self_links() == SelfLinkPolicy::Reject {
+                return Err(NewLinkError::SelfReference);
+            }
+            let (followed, links) = Self::follow_self_links(conn, url, policy).await?;
+            for hop in &links {
+                if visible_to.map_or(false, |owner| hop.owner().as_ref() != Some(owner)) {
+                    return Err(NewLinkError::SelfReference);
+                }
+                if hop.expires_at.is_some() || hop.max_visits.is_some() {
+                    return Err(NewLinkError::LimitedSelfReference);
+                }
+            }
+            url = followed;
+        }
+        policy.canonicalize(&mut url);
+        policy.check(&url)?;
+
+        Ok(url)
+    }
+
+    /// Follows `url` through this service's own short links until it leads
+    /// elsewhere, passing through at most `max_redirect_hops` `Link`s
+    ///
+    /// Returns where `url` leads along with every `Link` passed through, in
+    /// order. Fails when `url` points at a part of this service which is not a
+    /// `Link` that still redirects.
+    pub(crate) async fn follow_self_links(
+        conn: &mut PgConnection,
+        mut url: Url,
+        policy: &DestinationPolicy,
+    ) -> Result<(Url, Vec<Self>), NewLinkError> {
+        let mut hops = 0;
+        let mut links = Vec::new();
+
+        loop {
+            match policy.self_reference(&url) {
+                None => return Ok((url, links)),
+                Some(SelfReference::Other) => return Err(NewLinkError::SelfReference),
+                Some(SelfReference::ShortLink(_)) if hops == policy.max_redirect_hops() => {
+                    return Err(NewLinkError::RedirectLoop)
+                }
+                Some(SelfReference::ShortLink(hash)) => {
+                    let link = Self::get_by_hash(conn, &hash)
+                        .await
+                        .map_err(|_| NewLinkError::DatabaseError)?
+                        .filter(|link| !link.is_expired())
+                        .ok_or(NewLinkError::SelfReference)?;
+                    url = parse_destination(&link.destination)?;
+                    links.push(link);
+                    hops += 1;
+                }
+            }
         }
     }
 
+    /// Resolves where a visit to this `Link` leads, given the visit's `query`
+    ///
+    /// Any of this service's own short links the `destination` points at are
+    /// followed as described by [`Link::follow_self_links`], and returned too,
+    /// while a `destination` at any other part of this service is left for
+    /// the client to follow. Either way, query parameters are merged in as
+    /// described by [`Link::redirect_url`].
+    pub(crate) async fn resolve(
+        &self,
+        conn: &mut PgConnection,
+        policy: &DestinationPolicy,
+        query: Option<&str>,
+    ) -> Result<(Url, Vec<Self>), NewLinkError> {
+        let destination = parse_destination(&self.destination)?;
+        let (destination, hops) =
+            match Self::follow_self_links(conn, destination.clone(), policy).await {
+                Ok(followed) => followed,
+                Err(NewLinkError::SelfReference) => (destination, Vec::new()),
+                Err(err) => return Err(err),
+            };
+
+        Ok((self.redirect_url(destination, query), hops))
+    }
+
+    /// Takes a transaction-scoped lock on `destination`, serializing
+    /// concurrent creations of `Link`s which redirect there
+    async fn lock_destination(conn: &mut PgConnection, destination: &str) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"SELECT true AS "locked!" FROM pg_advisory_xact_lock(hashtext($1))"#,
+            destination
+        )
+        .fetch_one(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Whether any `Link` which has not been deleted, expired or used up its
+    /// visits already redirects to `destination`, whoever created it
+    async fn destination_taken(conn: &mut PgConnection, destination: &str) -> sqlx::Result<bool> {
+        sqlx::query_scalar!(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM links
+                WHERE destination = $1 AND deleted_at IS NULL
+                    AND (remaining_visits IS NULL OR remaining_visits > 0)
+                    AND (expires_at IS NULL OR expires_at > now())
+            ) AS "exists!""#,
+            destination
+        )
+        .fetch_one(conn)
+        .await
+    }
+
+    /// Fetches any `Link` created by the same caller which already redirects
+    /// to the destination of `link` with the same metadata, query options,
+    /// expiry and visit limit, so that it can be reused in place of `link`
+    ///
+    /// A `Link` which has expired or used up its visits is never returned.
+    async fn find_equivalent(conn: &mut PgConnection, link: &Link) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"SELECT id, destination, hash, expires_at, max_visits, remaining_visits,
+                deleted_at, created_at, clicks, title, description, tags, created_by,
+                created_by_subject, original_destination, forward_query, utm_source,
+                utm_medium, utm_campaign, utm_term, utm_content, redirect_type
+            FROM links
+            WHERE destination = $1 AND deleted_at IS NULL
+                AND created_by IS NOT DISTINCT FROM $2
+                AND created_by_subject IS NOT DISTINCT FROM $3
+                AND forward_query = $4
+                AND utm_source IS NOT DISTINCT FROM $5
+                AND utm_medium IS NOT DISTINCT FROM $6
+                AND utm_campaign IS NOT DISTINCT FROM $7
+                AND utm_term IS NOT DISTINCT FROM $8
+                AND utm_content IS NOT DISTINCT FROM $9
+                AND redirect_type = $10
+                AND max_visits IS NOT DISTINCT FROM $11
+                AND (remaining_visits IS NULL OR remaining_visits > 0)
+                AND expires_at IS NOT DISTINCT FROM $12
+                AND (expires_at IS NULL OR expires_at > now())
+                AND title IS NOT DISTINCT FROM $13
+                AND description IS NOT DISTINCT FROM $14
+                AND tags = $15
+            ORDER BY created_at, id LIMIT 1"#,
+            link.destination,
+            link.created_by,
+            link.created_by_subject,
+            link.forward_query,
+            link.utm_source,
+            link.utm_medium,
+            link.utm_campaign,
+            link.utm_term,
+            link.utm_content,
+            link.redirect_type,
+            link.max_visits,
+            link.expires_at,
+            link.title,
+            link.description,
+            &link.tags
+        )
+        .fetch_optional(conn)
+        .await
+    }
+
     /// Inserts a well-formed `Link` into the database, returning a [`Result`] over the `Link` type
     #[instrument(skip(conn))]
     pub(crate) async fn insert(conn: &mut PgConnection, link: Link) -> Result<Self, NewLinkError> {
         sqlx::query_as!(
             Self,
-            r#"INSERT INTO links (id, destination, hash)
-            VALUES ($1, $2, $3)
-            RETURNING id, destination, hash
+            r#"INSERT INTO links (id, destination, hash, expires_at, max_visits, remaining_visits,
+                created_at, title, description, tags, created_by, created_by_subject,
+                original_destination, forward_query, utm_source, utm_medium, utm_campaign,
+                utm_term, utm_content, redirect_type)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17,
+                $18, $19, $20)
+            RETURNING id, destination, hash, expires_at, max_visits, remaining_visits,
+                deleted_at, created_at, clicks, title, description, tags, created_by,
+                created_by_subject, original_destination, forward_query, utm_source,
+                utm_medium, utm_campaign, utm_term, utm_content, redirect_type
             "#,
             link.id,
             link.destination,
-            link.hash
+            link.hash,
+            link.expires_at,
+            link.max_visits,
+            link.remaining_visits,
+            link.created_at,
+            link.title,
+            link.description,
+            &link.tags,
+            link.created_by,
+            link.created_by_subject,
+            link.original_destination,
+            link.forward_query,
+            link.utm_source,
+            link.utm_medium,
+            link.utm_campaign,
+            link.utm_term,
+            link.utm_content,
+            link.redirect_type
         )
         .fetch_one(conn)
         .await
+        .map_err(|err| match err {
+            sqlx::Error::Database(db_err) if db_err.constraint() == Some("links_hash") => {
+                NewLinkError::SlugTaken
+            }
+            _ => NewLinkError::DatabaseError,
+        })
+    }
+
+    /// Applies a [`LinkUpdate`] to the `Link` with a given `hash`, returning
+    /// the updated `Link` if one exists
+    ///
+    /// A new `destination` or set of `tags` is validated exactly as it would be
+    /// for a [`NewLink`].
+    #[instrument(skip(conn))]
+    pub(crate) async fn update(
+        conn: &mut PgConnection,
+        hash: &str,
+        update: LinkUpdate,
+        policy: &DestinationPolicy,
+    ) -> Result<Option<Self>, NewLinkError> {
+        let destination = match &update.destination {
+            Some(destination) => Some(
+                Self::validate_destination(conn, destination, policy, update.visible_to.as_ref())
+                    .await?
+                    .to_string(),
+            ),
+            None => None,
+        };
+        let tags = update.tags.as_deref().map(normalize_tags).transpose()?;
+
+        sqlx::query_as!(
+            Self,
+            r#"UPDATE links SET
+                destination = COALESCE($2, destination),
+                original_destination = COALESCE($6, original_destination),
+                title = CASE WHEN $3::text IS NULL THEN title ELSE NULLIF($3, '') END,
+                description = CASE WHEN $4::text IS NULL THEN description ELSE NULLIF($4, '') END,
+                tags = COALESCE($5, tags),
+                forward_query = COALESCE($7, forward_query),
+                utm_source = CASE WHEN $8::text IS NULL THEN utm_source ELSE NULLIF($8, '') END,
+                utm_medium = CASE WHEN $9::text IS NULL THEN utm_medium ELSE NULLIF($9, '') END,
+                utm_campaign = CASE WHEN $10::text IS NULL THEN utm_campaign
+                    ELSE NULLIF($10, '') END,
+                utm_term = CASE WHEN $11::text IS NULL THEN utm_term ELSE NULLIF($11, '') END,
+                utm_content = CASE WHEN $12::text IS NULL THEN utm_content
+                    ELSE NULLIF($12, '') END,
+                redirect_type = COALESCE($13, redirect_type)
+            WHERE hash = $1 AND deleted_at IS NULL
+            RETURNING id, destination, hash, expires_at, max_visits, remaining_visits,
+                deleted_at, created_at, clicks, title, description, tags, created_by,
+                created_by_subject, original_destination, forward_query, utm_source,
+                utm_medium, utm_campaign, utm_term, utm_content, redirect_type"#,
+            hash,
+            destination,
+            update.title,
+            update.description,
+            tags.as_deref(),
+            update.destination,
+            update.forward_query,
+            update.utm_source,
+            update.utm_medium,
+            update.utm_campaign,
+            update.utm_term,
+            update.utm_content,
+            update
+                .redirect_type
+                .map(RedirectType::code)
+                .map(redirect_code)
+        )
+        .fetch_optional(conn)
+        .await
         .map_err(|_| NewLinkError::DatabaseError)
     }
 
-    /// Fetches a `Link` with a given `hash`, if one exists
+    /// Fetches a `Link` with a given `hash`, if one exists and has not been deleted
     ///
     /// The most common way of retrieving `Link`s for this use-case.
     #[instrument(skip(conn))]
     pub(crate) async fn get_by_hash(
         conn: &mut PgConnection,
         hash: &str,
+    ) -> sqlx::Result<Option<Self>> {
+        Ok(Self::get_by_hash_including_deleted(conn, hash)
+            .await?
+            .filter(|link| !link.is_deleted()))
+    }
+
+    /// Fetches a `Link` with a given `hash`, if one exists, even if it has
+    /// been soft-deleted
+    #[instrument(skip(conn))]
+    pub(crate) async fn get_by_hash_including_deleted(
+        conn: &mut PgConnection,
+        hash: &str,
     ) -> sqlx::Result<Option<Self>> {
         sqlx::query_as!(
             Self,
-            "SELECT id, destination, hash FROM links WHERE hash = $1",
+            r#"SELECT id, destination, hash, expires_at, max_visits, remaining_visits,
+                deleted_at, created_at, clicks, title, description, tags, created_by,
+                created_by_subject, original_destination, forward_query, utm_source,
+                utm_medium, utm_campaign, utm_term, utm_content, redirect_type
+            FROM links WHERE hash = $1"#,
             hash
         )
         .fetch_optional(conn)
         .await
     }
 
-    /// Lists all previously recorded `Link`s without filtering, access control, or other qualification
+    /// Soft-deletes the `Link` with a given `hash`, returning whether one was deleted
+    ///
+    /// The row is kept, and its `hash` stays reserved, until it is either
+    /// restored or purged by [`Link::purge_deleted`].
+    #[instrument(skip(conn))]
+    pub(crate) async fn delete(conn: &mut PgConnection, hash: &str) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE links SET deleted_at = now() WHERE hash = $1 AND deleted_at IS NULL",
+            hash
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Reverses a soft-delete of the `Link` with a given `hash`, returning the
+    /// restored `Link` if one was deleted
     #[instrument(skip(conn))]
-    pub(crate) async fn list(conn: &mut PgConnection) -> sqlx::Result<Vec<Self>> {
+    pub(crate) async fn restore(conn: &mut PgConnection, hash: &str) -> sqlx::Result<Option<Self>> {
         sqlx::query_as!(
             Self,
-            "SELECT id, destination, hash FROM links ORDER BY destination"
+            r#"UPDATE links SET deleted_at = NULL
+            WHERE hash = $1 AND deleted_at IS NOT NULL
+            RETURNING id, destination, hash, expires_at, max_visits, remaining_visits,
+                deleted_at, created_at, clicks, title, description, tags, created_by,
+                created_by_subject, original_destination, forward_query, utm_source,
+                utm_medium, utm_campaign, utm_term, utm_content, redirect_type"#,
+            hash
         )
-        .fetch_all(conn)
+        .fetch_optional(conn)
         .await
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::test_helpers::test_db;
-    use anyhow::Result;
+    /// Permanently deletes all `Link`s which were soft-deleted before
+    /// `cutoff`, returning how many were removed
+    #[instrument(skip(conn))]
+    pub(crate) async fn purge_deleted(
+        conn: &mut PgConnection,
+        cutoff: DateTime<Utc>,
+    ) -> sqlx::Result<u64> {
+        let result = sqlx::query!("DELETE FROM links WHERE deleted_at < $1", cutoff)
+            .execute(conn)
+            .await?;
 
-    #[test]
-    fn test_new() -> Result<()> {
-        let url = Url::parse("https://www.google.com")?;
-        assert_eq!(url.to_string(), Link::new(&url).destination);
-        Ok(())
+        Ok(result.rows_affected())
     }
 
-    #[tokio::test]
-    async fn test_insert() -> Result<()> {
-        let pool = test_db().await?;
-        let mut conn = pool.begin().await?;
+    /// Reports whether `slug` is valid and not already used as the `hash` of an existing `Link`
+    #[instrument(skip(conn))]
+    pub(crate) async fn slug_availability(
+        conn: &mut PgConnection,
+        slug: &str,
+    ) -> sqlx::Result<SlugAvailability> {
+        if let Err(err) = validate_slug(slug) {
+            return Ok(SlugAvailability {
+                slug: slug.to_owned(),
+                available: false,
+                reason: Some(err.to_string()),
+            });
+        }
 
-        let url = Url::parse("https://www.google.com")?;
-        let link = Link::new(&url);
+        let taken = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM links WHERE hash = $1) AS "exists!""#,
+            slug
+        )
+        .fetch_one(conn)
+        .await?;
+
+        Ok(SlugAvailability {
+            slug: slug.to_owned(),
+            available: !taken,
+            reason: None,
+        })
+    }
+
+    /// Streams every `Link` which has not been deleted, oldest first, without
+    /// holding them all in memory
+    ///
+    /// Expired `Link`s, including those with no remaining visits, are omitted
+    /// unless `include_expired` is set. When `created_by` is given, only
+    /// `Link`s created by that caller are included.
+    pub(crate) fn export<'a>(
+        conn: &'a mut PgConnection,
+        include_expired: bool,
+        created_by: Option<&'a Owner>,
+    ) -> BoxStream<'a, sqlx::Result<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"SELECT id, destination, hash, expires_at, max_visits, remaining_visits,
+                deleted_at, created_at, clicks, title, description, tags, created_by,
+                created_by_subject, original_destination, forward_query, utm_source,
+                utm_medium, utm_campaign, utm_term, utm_content, redirect_type
+            FROM links
+            WHERE deleted_at IS NULL AND ($1 OR (
+                (expires_at IS NULL OR expires_at > now())
+                AND (remaining_visits IS NULL OR remaining_visits > 0)
+            ))
+            AND ($2::uuid IS NULL OR created_by = $2)
+            AND ($3::text IS NULL OR created_by_subject = $3)
+            ORDER BY created_at, id"#,
+            include_expired,
+            created_by.and_then(Owner::api_key),
+            created_by.and_then(Owner::subject)
+        )
+        .fetch(conn)
+    }
+
+    /// Searches destinations of `Link`s which have not been deleted, most
+    /// relevant first
+    ///
+    /// Relevance is the trigram word similarity between the query and either
+    /// the destination or the title and description, whichever is closer. Only
+    /// `fuzzy` mode matches the title and description. In `domain` mode, exact
+    /// host matches rank above subdomains.
+    #[instrument(skip(conn))]
+    pub(crate) async fn search(
+        conn: &mut PgConnection,
+        search: &LinkSearch,
+    ) -> Result<Vec<Self>, ListError> {
+        let q = search.q.trim();
+        if q.is_empty() {
+            return Err(ListError::EmptyQuery);
+        }
+        let limit = search
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+
+        let mut builder = QueryBuilder::new(
+            r#"SELECT id, destination, hash, expires_at, max_visits, remaining_visits,
+                deleted_at, created_at, clicks, title, description, tags, created_by,
+                created_by_subject, original_destination, forward_query, utm_source,
+                utm_medium, utm_campaign, utm_term, utm_content, redirect_type
+            FROM links WHERE deleted_at IS NULL"#,
+        );
+
+        if let Some(tag) = &search.tag {
+            builder
+                .push(" AND tags @> ARRAY[")
+                .push_bind(tag.trim().to_lowercase())
+                .push("]");
+        }
+        if let Some(created_by) = &search.created_by {
+            created_by.push_condition(&mut builder);
+        }
+
+        let domain = q.trim_end_matches('.').to_lowercase();
+        match search.mode {
+            SearchMode::Domain => {
+                builder
+                    .push(" AND (destination_host = ")
+                    .push_bind(domain.clone())
+                    .push(" OR destination_host LIKE ")
+                    .push_bind(format!("%.{}", escape_like(&domain)))
+                    .push(")");
+            }
+            SearchMode::Prefix => {
+                let prefix = escape_like(q);
+                builder
+                    .push(" AND (destination ILIKE ")
+                    .push_bind(format!("{}%", prefix))
+                    .push(" OR destination ILIKE ")
+                    .push_bind(format!("%://{}%", prefix))
+                    .push(")");
+            }
+            SearchMode::Fuzzy => {
+                builder
+                    .push(" AND (")
+                    .push_bind(q.to_owned())
+                    .push(" <% destination OR ")
+                    .push_bind(q.to_owned())
+                    .push(format_args!(" <% {})", METADATA_TEXT));
+            }
+        }
+
+        builder.push(" ORDER BY ");
+        if search.mode == SearchMode::Domain {
+            builder
+                .push("destination_host = ")
+                .push_bind(domain)
+                .push(" DESC, ");
+        }
+        builder
+            .push("greatest(word_similarity(")
+            .push_bind(q.to_owned())
+            .push(", destination), word_similarity(")
+            .push_bind(q.to_owned())
+            .push(format_args!(
+                ", {})) DESC, created_at DESC, id LIMIT ",
+                METADATA_TEXT
+            ))
+            .push_bind(limit);
+
+        Ok(builder.build_query_as::<Self>().fetch_all(conn).await?)
+    }
+
+    /// Lists one page of previously recorded `Link`s matching a [`LinkQuery`]
+    ///
+    /// Pages are fetched with keyset pagination over the sort column and `id`,
+    /// so each page costs the same regardless of how deep into the results it
+    /// is. Deleted `Link`s are always omitted. Expired `Link`s, including those
+    /// with no remaining visits, are omitted unless `include_expired` is set.
+    #[instrument(skip(conn))]
+    pub(crate) async fn list(
+        conn: &mut PgConnection,
+        query: &LinkQuery,
+    ) -> Result<LinkPage, ListError> {
+        let cursor = query.cursor()?;
+        let limit = query.limit();
+
+        let mut builder = QueryBuilder::new(
+            r#"SELECT id, destination, hash, expires_at, max_visits, remaining_visits,
+                deleted_at, created_at, clicks, title, description, tags, created_by,
+                created_by_subject, original_destination, forward_query, utm_source,
+                utm_medium, utm_campaign, utm_term, utm_content, redirect_type
+            FROM links WHERE deleted_at IS NULL"#,
+        );
+
+        if !query.include_expired {
+            builder.push(
+                r#" AND (expires_at IS NULL OR expires_at > now())
+                AND (remaining_visits IS NULL OR remaining_visits > 0)"#,
+            );
+        }
+        if let Some(domain) = &query.domain {
+            let domain = domain.trim().trim_end_matches('.').to_lowercase();
+            builder
+                .push(" AND (destination_host = ")
+                .push_bind(domain.clone())
+                .push(" OR destination_host LIKE ")
+                .push_bind(format!("%.{}", escape_like(&domain)))
+                .push(")");
+        }
+        if let Some(tag) = &query.tag {
+            builder
+                .push(" AND tags @> ARRAY[")
+                .push_bind(tag.trim().to_lowercase())
+                .push("]");
+        }
+        if let Some(created_by) = &query.created_by {
+            created_by.push_condition(&mut builder);
+        }
+        if let Some(created_before) = query.created_before {
+            builder.push(" AND created_at < ").push_bind(created_before);
+        }
+        if let Some(created_after) = query.created_after {
+            builder.push(" AND created_at > ").push_bind(created_after);
+        }
+
+        let (column, direction) = query.sort.order();
+        if let Some(cursor) = cursor {
+            let comparison = if direction == "ASC" { ">" } else { "<" };
+            builder.push(format_args!(" AND ({}, id) {} (", column, comparison));
+            match cursor.key {
+                CursorKey::CreatedAt(created_at) => builder.push_bind(created_at),
+                CursorKey::Destination(destination) => builder.push_bind(destination),
+                CursorKey::Clicks(clicks) => builder.push_bind(clicks),
+            };
+            builder.push(", ").push_bind(cursor.id).push(")");
+        }
+
+        builder.push(format_args!(
+            " ORDER BY {column} {direction}, id {direction} LIMIT ",
+            column = column,
+            direction = direction
+        ));
+        // fetch one extra row to learn whether there is another page
+        builder.push_bind(limit + 1);
+
+        let mut links = builder.build_query_as::<Self>().fetch_all(conn).await?;
+
+        let next_cursor = if links.len() > usize::try_from(limit).unwrap_or(usize::MAX) {
+            links.pop();
+            links
+                .last()
+                .map(|last| query.sort.cursor_after(last).encode())
+        } else {
+            None
+        };
+
+        Ok(LinkPage { links, next_cursor })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::test_db;
+    use anyhow::Result;
+
+    #[test]
+    fn test_new() -> Result<()> {
+        let url = Url::parse("https://www.google.com")?;
+        assert_eq!(url.to_string(), Link::new(&url).destination);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_insert() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let url = Url::parse("https://www.google.com")?;
+        let link = Link::new(&url);
         let inserted = Link::insert(&mut conn, link.clone()).await?;
 
         assert_eq!(inserted, link);
         Ok(())
     }
 
+    #[test]
+    fn test_try_from_with_slug() -> Result<()> {
+        let new = NewLink {
+            destination: "https://www.google.com".to_owned(),
+            slug: Some("spring-sale".to_owned()),
+            ..NewLink::default()
+        };
+        let link = Link::try_from(new)?;
+
+        assert_eq!(link.hash, "spring-sale");
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_slug() {
+        assert!(validate_slug("spring_sale-2022").is_ok());
+        assert!(validate_slug("ab").is_err());
+        assert!(validate_slug(&"a".repeat(MAX_SLUG_LENGTH + 1)).is_err());
+        assert!(validate_slug("spring sale").is_err());
+        assert!(validate_slug("sale/spring").is_err());
+        assert!(validate_slug("Health").is_err());
+        assert!(validate_slug("export").is_err());
+        assert!(validate_slug("search").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_insert_taken_slug() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let mut first = Link::new(&Url::parse("https://www.google.com")?);
+        first.hash = "taken".to_owned();
+        Link::insert(&mut conn, first).await?;
+
+        let availability = Link::slug_availability(&mut conn, "taken").await?;
+        assert!(!availability.available);
+
+        let mut second = Link::new(&Url::parse("https://www.bing.com")?);
+        second.hash = "taken".to_owned();
+        let result = Link::insert(&mut conn, second).await;
+
+        assert!(matches!(result, Err(NewLinkError::SlugTaken)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_retries_collisions() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        for (hash, destination) in [
+            ("a", "https://www.google.com"),
+            ("b", "https://www.bing.com"),
+        ] {
+            let mut link = Link::new(&Url::parse(destination)?);
+            link.hash = hash.to_owned();
+            Link::insert(&mut conn, link).await?;
+        }
+
+        let slugs = Slugs::new(&crate::config::LinksConfig {
+            hash_length: 1,
+            hash_alphabet: "ab".to_owned(),
+            ..Default::default()
+        })?;
+        let new = NewLink {
+            destination: "https://duckduckgo.com".to_owned(),
+            ..NewLink::default()
+        };
+        let created = Link::create(
+            &mut conn,
+            new,
+            &slugs,
+            DuplicatePolicy::Reuse,
+            &DestinationPolicy::default(),
+        )
+        .await?;
+
+        assert!(matches!(created, Created::Inserted(link) if link.hash.len() == 2));
+        assert_eq!(
+            Link::list(&mut conn, &LinkQuery::default())
+                .await?
+                .links
+                .len(),
+            3
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_duplicate_destination() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+        let slugs = Slugs::new(&crate::config::LinksConfig::default())?;
+        let new = NewLink {
+            destination: "https://www.google.com".to_owned(),
+            ..NewLink::default()
+        };
+
+        let first = match Link::create(
+            &mut conn,
+            new.clone(),
+            &slugs,
+            DuplicatePolicy::Reuse,
+            &DestinationPolicy::default(),
+        )
+        .await?
+        {
+            Created::Inserted(link) => link,
+            Created::Existing(link) => panic!("unexpectedly reused {:?}", link),
+        };
+
+        let reused = Link::create(
+            &mut conn,
+            new.clone(),
+            &slugs,
+            DuplicatePolicy::Reuse,
+            &DestinationPolicy::default(),
+        )
+        .await?;
+        assert_eq!(reused, Created::Existing(first.clone()));
+
+        let rejected = Link::create(
+            &mut conn,
+            new.clone(),
+            &slugs,
+            DuplicatePolicy::Reject,
+            &DestinationPolicy::default(),
+        )
+        .await;
+        assert!(matches!(rejected, Err(NewLinkError::DestinationTaken)));
+
+        let vanity = NewLink {
+            slug: Some("spring-sale".to_owned()),
+            ..new.clone()
+        };
+        let created = Link::create(
+            &mut conn,
+            vanity,
+            &slugs,
+            DuplicatePolicy::Reuse,
+            &DestinationPolicy::default(),
+        )
+        .await?;
+        assert!(matches!(created, Created::Inserted(link) if link.hash == "spring-sale"));
+
+        // a link which expires is not interchangeable with a permanent one
+        let expiring = NewLink {
+            expires_at: Some(Utc::now().trunc_subsecs(6) + chrono::Duration::days(1)),
+            ..new.clone()
+        };
+        let temporary = match Link::create(
+            &mut conn,
+            expiring.clone(),
+            &slugs,
+            DuplicatePolicy::Reuse,
+            &DestinationPolicy::default(),
+        )
+        .await?
+        {
+            Created::Inserted(link) => link,
+            Created::Existing(link) => panic!("unexpectedly reused {:?}", link),
+        };
+        assert_ne!(temporary.id, first.id);
+        let reused = Link::create(
+            &mut conn,
+            expiring,
+            &slugs,
+            DuplicatePolicy::Reuse,
+            &DestinationPolicy::default(),
+        )
+        .await?;
+        assert_eq!(reused, Created::Existing(temporary));
+
+        let mut expired = Link::new(&Url::parse("https://www.google.com")?);
+        expired.expires_at = Some(Utc::now() - chrono::Duration::minutes(5));
+        let expired = Link::insert(&mut conn, expired).await?;
+        assert_eq!(Link::find_equivalent(&mut conn, &expired).await?, None);
+
+        let created = Link::create(
+            &mut conn,
+            new,
+            &slugs,
+            DuplicatePolicy::Create,
+            &DestinationPolicy::default(),
+        )
+        .await?;
+        assert!(matches!(created, Created::Inserted(link) if link != first));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_visit_limited_duplicate() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+        let slugs = Slugs::new(&crate::config::LinksConfig::default())?;
+        let policy = DestinationPolicy::default();
+        let unlimited = NewLink {
+            destination: "https://www.google.com".to_owned(),
+            ..NewLink::default()
+        };
+        let one_time = NewLink {
+            max_visits: Some(1),
+            ..unlimited.clone()
+        };
+
+        let first = match Link::create(
+            &mut conn,
+            unlimited,
+            &slugs,
+            DuplicatePolicy::Reuse,
+            &policy,
+        )
+        .await?
+        {
+            Created::Inserted(link) | Created::Existing(link) => link,
+        };
+        let once = match Link::create(
+            &mut conn,
+            one_time.clone(),
+            &slugs,
+            DuplicatePolicy::Reuse,
+            &policy,
+        )
+        .await?
+        {
+            Created::Inserted(link) => link,
+            Created::Existing(link) => panic!("unexpectedly reused {:?}", link),
+        };
+        assert_ne!(once.id, first.id);
+        assert_eq!(once.remaining_visits, Some(1));
+
+        // a one-time link which has been visited is not handed out again
+        assert!(once.claim_visit(&mut conn).await?);
+        let created =
+            Link::create(&mut conn, one_time, &slugs, DuplicatePolicy::Reuse, &policy).await?;
+        assert!(matches!(created, Created::Inserted(link) if link.id != once.id));
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_from_with_expiry() -> Result<()> {
+        let expires_at = Utc::now() + chrono::Duration::days(1);
+        let new = NewLink {
+            destination: "https://www.google.com".to_owned(),
+            expires_at: Some(expires_at),
+            ..NewLink::default()
+        };
+        let link = Link::try_from(new.clone())?;
+        assert_eq!(link.expires_at, Some(expires_at));
+        assert!(!link.is_expired());
+
+        let expired = NewLink {
+            expires_at: Some(Utc::now() - chrono::Duration::seconds(1)),
+            ..new
+        };
+        assert!(matches!(
+            Link::try_from(expired),
+            Err(NewLinkError::InvalidExpiry)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_expired() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let mut expired = Link::new(&Url::parse("https://www.google.com")?);
+        expired.expires_at = Some(Utc::now() - chrono::Duration::minutes(5));
+        let expired = Link::insert(&mut conn, expired).await?;
+        assert!(expired.is_expired());
+
+        let include_expired = LinkQuery {
+            include_expired: true,
+            ..LinkQuery::default()
+        };
+
+        assert!(Link::list(&mut conn, &LinkQuery::default())
+            .await?
+            .links
+            .is_empty());
+        assert_eq!(
+            Link::list(&mut conn, &include_expired).await?.links,
+            vec![expired]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_claim_visit() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let new = NewLink {
+            destination: "https://www.google.com".to_owned(),
+            max_visits: Some(2),
+            ..NewLink::default()
+        };
+        let link = Link::insert(&mut conn, Link::try_from(new)?).await?;
+
+        assert!(link.claim_visit(&mut conn).await?);
+        assert!(link.claim_visit(&mut conn).await?);
+        assert!(!link.claim_visit(&mut conn).await?);
+
+        let exhausted = Link::get_by_hash(&mut conn, &link.hash)
+            .await?
+            .expect("link should exist");
+        assert_eq!(exhausted.remaining_visits, Some(0));
+        assert!(exhausted.is_expired());
+        assert!(Link::list(&mut conn, &LinkQuery::default())
+            .await?
+            .links
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_claim_visits() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let limited = |max_visits| NewLink {
+            destination: "https://www.google.com".to_owned(),
+            max_visits: Some(max_visits),
+            ..NewLink::default()
+        };
+        let once = Link::insert(&mut conn, Link::try_from(limited(1))?).await?;
+        let twice = Link::insert(&mut conn, Link::try_from(limited(2))?).await?;
+        let unlimited =
+            Link::insert(&mut conn, Link::new(&Url::parse("https://www.google.com")?)).await?;
+
+        assert!(Link::claim_visits(&mut conn, &[&twice, &once, &unlimited]).await?);
+        assert!(!Link::claim_visits(&mut conn, &[&twice, &once, &unlimited]).await?);
+
+        // the refused visit didn't use up the last visit of `twice`
+        let twice = Link::get_by_hash(&mut conn, &twice.hash)
+            .await?
+            .expect("link should exist");
+        assert_eq!(twice.remaining_visits, Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let link =
+            Link::insert(&mut conn, Link::new(&Url::parse("https://www.google.com")?)).await?;
+
+        let unchanged = Link::update(
+            &mut conn,
+            &link.hash,
+            LinkUpdate::default(),
+            &DestinationPolicy::default(),
+        )
+        .await?;
+        assert_eq!(unchanged.as_ref(), Some(&link));
+
+        let update = LinkUpdate {
+            destination: Some("https://www.bing.com".to_owned()),
+            ..LinkUpdate::default()
+        };
+        let updated = Link::update(
+            &mut conn,
+            &link.hash,
+            update.clone(),
+            &DestinationPolicy::default(),
+        )
+        .await?
+        .expect("link should exist");
+        assert_eq!(updated.destination, "https://www.bing.com/");
+        assert_eq!(updated.id, link.id);
+
+        let invalid = LinkUpdate {
+            destination: Some("not a url".to_owned()),
+            ..LinkUpdate::default()
+        };
+        let result = Link::update(
+            &mut conn,
+            &link.hash,
+            invalid,
+            &DestinationPolicy::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(NewLinkError::InvalidUrl)));
+
+        let query_options = LinkUpdate {
+            forward_query: Some(true),
+            utm_source: Some("newsletter".to_owned()),
+            ..LinkUpdate::default()
+        };
+        let updated = Link::update(
+            &mut conn,
+            &link.hash,
+            query_options,
+            &DestinationPolicy::default(),
+        )
+        .await?
+        .expect("link should exist");
+        assert!(updated.forward_query);
+        assert_eq!(updated.utm_source.as_deref(), Some("newsletter"));
+
+        let cleared = LinkUpdate {
+            utm_source: Some(String::new()),
+            ..LinkUpdate::default()
+        };
+        let updated = Link::update(
+            &mut conn,
+            &link.hash,
+            cleared,
+            &DestinationPolicy::default(),
+        )
+        .await?
+        .expect("link should exist");
+        assert!(updated.forward_query);
+        assert_eq!(updated.utm_source, None);
+
+        let unsafe_destination = LinkUpdate {
+            destination: Some("javascript:alert(1)".to_owned()),
+            ..LinkUpdate::default()
+        };
+        let result = Link::update(
+            &mut conn,
+            &link.hash,
+            unsafe_destination,
+            &DestinationPolicy::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(NewLinkError::DisallowedScheme(_))));
+
+        assert_eq!(
+            Link::update(&mut conn, "missing", update, &DestinationPolicy::default()).await?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_redirect_url() -> Result<()> {
+        let destination = Url::parse("https://example.com/a?id=7&utm_source=site&utm_medium=x")?;
+        let mut link = Link::new(&destination);
+        let redirect =
+            |link: &Link, query| link.redirect_url(destination.clone(), query).to_string();
+
+        assert_eq!(
+            redirect(&link, Some("utm_campaign=spring")),
+            "https://example.com/a?id=7&utm_source=site&utm_medium=x"
+        );
+
+        link.utm_source = Some("news letter".to_owned());
+        link.utm_campaign = Some("base".to_owned());
+        assert_eq!(
+            redirect(&link, None),
+            "https://example.com/a?id=7&utm_medium=x&utm_source=news+letter&utm_campaign=base"
+        );
+
+        link.forward_query = true;
+        assert_eq!(
+            redirect(&link, Some("utm_campaign=spring&tag=a&tag=b&id=8")),
+            "https://example.com/a?utm_medium=x&utm_source=news+letter&utm_campaign=spring&tag=a&tag=b&id=8"
+        );
+        assert_eq!(
+            redirect(&link, Some("utm%5Fcampaign=fall&")),
+            "https://example.com/a?id=7&utm_medium=x&utm_source=news+letter&utm%5Fcampaign=fall"
+        );
+
+        let bare = Link::new(&Url::parse("https://example.com/")?);
+        assert_eq!(
+            bare.redirect_url(Url::parse("https://example.com/")?, Some("a=1")),
+            Url::parse("https://example.com/")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_redirect_type() -> Result<()> {
+        let mut link = Link::new(&Url::parse("https://example.com/")?);
+        assert_eq!(link.redirect_type(), RedirectType::TemporaryRedirect);
+        assert_eq!(link.cache_max_age(600), None);
+
+        let permanent: NewLink = serde_json::from_str(
+            r#"{"destination": "https://example.com/", "redirect_type": 308}"#,
+        )?;
+        link = Link::try_from(permanent)?;
+        assert_eq!(link.redirect_type, 308);
+        assert_eq!(link.cache_max_age(600), Some(600));
+
+        link.expires_at = Some(Utc::now() + chrono::Duration::seconds(60));
+        assert!(matches!(link.cache_max_age(600), Some(age) if age <= 60));
+        link.expires_at = None;
+        link.max_visits = Some(10);
+        assert_eq!(link.cache_max_age(600), None);
+
+        assert!(serde_json::from_str::<NewLink>(
+            r#"{"destination": "https://example.com/", "redirect_type": 303}"#
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_and_restore() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let link =
+            Link::insert(&mut conn, Link::new(&Url::parse("https://www.google.com")?)).await?;
+
+        let include_expired = LinkQuery {
+            include_expired: true,
+            ..LinkQuery::default()
+        };
+        assert!(Link::delete(&mut conn, &link.hash).await?);
+        assert!(!Link::delete(&mut conn, &link.hash).await?);
+        assert_eq!(Link::get_by_hash(&mut conn, &link.hash).await?, None);
+        assert!(Link::list(&mut conn, &include_expired)
+            .await?
+            .links
+            .is_empty());
+
+        let deleted = Link::get_by_hash_including_deleted(&mut conn, &link.hash)
+            .await?
+            .expect("deleted link should still exist");
+        assert!(deleted.is_deleted());
+
+        let restored = Link::restore(&mut conn, &link.hash).await?;
+        assert_eq!(restored, Some(link.clone()));
+        assert_eq!(Link::restore(&mut conn, &link.hash).await?, None);
+        assert_eq!(Link::get_by_hash(&mut conn, &link.hash).await?, Some(link));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_purge_deleted() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let kept =
+            Link::insert(&mut conn, Link::new(&Url::parse("https://www.google.com")?)).await?;
+        let purged =
+            Link::insert(&mut conn, Link::new(&Url::parse("https://www.bing.com")?)).await?;
+        Link::delete(&mut conn, &purged.hash).await?;
+
+        let cutoff = Utc::now() - chrono::Duration::days(1);
+        assert_eq!(Link::purge_deleted(&mut conn, cutoff).await?, 0);
+
+        let cutoff = Utc::now() + chrono::Duration::days(1);
+        assert_eq!(Link::purge_deleted(&mut conn, cutoff).await?, 1);
+        assert_eq!(
+            Link::get_by_hash_including_deleted(&mut conn, &purged.hash).await?,
+            None
+        );
+        assert_eq!(
+            Link::list(&mut conn, &LinkQuery::default()).await?.links,
+            vec![kept]
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_list() -> Result<()> {
         let pool = test_db().await?;
@@ -141,9 +2150,594 @@ mod tests {
         let link = Link::new(&url);
         let inserted = Link::insert(&mut conn, link).await?;
 
-        let list = Link::list(&mut conn).await?;
+        let list = Link::list(&mut conn, &LinkQuery::default()).await?;
+
+        assert_eq!(list.links, vec![inserted]);
+        assert_eq!(list.next_cursor, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_pages() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let mut inserted = Vec::new();
+        for destination in [
+            "https://www.google.com",
+            "https://mail.google.com",
+            "https://www.bing.com",
+        ] {
+            inserted.push(Link::insert(&mut conn, Link::new(&Url::parse(destination)?)).await?);
+        }
+
+        let mut query = LinkQuery {
+            sort: LinkSort::Destination,
+            limit: Some(2),
+            ..LinkQuery::default()
+        };
+        let first = Link::list(&mut conn, &query).await?;
+        assert_eq!(first.links, vec![inserted[1].clone(), inserted[2].clone()]);
+
+        query.cursor = first.next_cursor;
+        let second = Link::list(&mut conn, &query).await?;
+        assert_eq!(second.links, vec![inserted[0].clone()]);
+        assert_eq!(second.next_cursor, None);
+
+        query.sort = LinkSort::Clicks;
+        assert!(matches!(
+            Link::list(&mut conn, &query).await,
+            Err(ListError::InvalidCursor)
+        ));
+
+        let query = LinkQuery {
+            domain: Some("Google.com".to_owned()),
+            sort: LinkSort::DestinationDesc,
+            ..LinkQuery::default()
+        };
+        let google = Link::list(&mut conn, &query).await?;
+        assert_eq!(google.links, vec![inserted[0].clone(), inserted[1].clone()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_domain() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let link = Link::insert(
+            &mut conn,
+            Link::new(&Url::parse("https://www.my-site.com")?),
+        )
+        .await?;
+
+        for (domain, expected) in [
+            ("my-site.com", vec![link.clone()]),
+            ("My-Site.com.", vec![link.clone()]),
+            ("www.my-site.com", vec![link]),
+            // `LIKE` wildcards only match themselves
+            ("my_site.com", vec![]),
+            ("%", vec![]),
+        ] {
+            let query = LinkQuery {
+                domain: Some(domain.to_owned()),
+                ..LinkQuery::default()
+            };
+            assert_eq!(Link::list(&mut conn, &query).await?.links, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export() -> Result<()> {
+        use futures::TryStreamExt;
+
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let first =
+            Link::insert(&mut conn, Link::new(&Url::parse("https://www.google.com")?)).await?;
+        let mut second = Link::new(&Url::parse("https://www.bing.com")?);
+        second.created_at = first.created_at + chrono::Duration::seconds(1);
+        let second = Link::insert(&mut conn, second).await?;
+
+        let exported: Vec<_> = Link::export(&mut conn, false, None).try_collect().await?;
+        assert_eq!(exported, vec![first, second]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_escape_like() {
+        assert_eq!(escape_like("100%_off\\"), "100\\%\\_off\\\\");
+    }
+
+    #[tokio::test]
+    async fn test_search() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let mut inserted = Vec::new();
+        for destination in [
+            "https://docs.example.com/guide/install",
+            "https://example.com/pricing",
+            "https://www.notexample.com/",
+        ] {
+            inserted.push(Link::insert(&mut conn, Link::new(&Url::parse(destination)?)).await?);
+        }
+
+        let search = |q: &str, mode| LinkSearch {
+            q: q.to_owned(),
+            mode,
+            ..LinkSearch::default()
+        };
+
+        let domain = Link::search(&mut conn, &search("Example.com", SearchMode::Domain)).await?;
+        assert_eq!(domain, vec![inserted[1].clone(), inserted[0].clone()]);
+
+        let prefix = Link::search(
+            &mut conn,
+            &search("docs.example.com/gu", SearchMode::Prefix),
+        )
+        .await?;
+        assert_eq!(prefix, vec![inserted[0].clone()]);
+
+        let fuzzy = Link::search(&mut conn, &search("instal", SearchMode::Fuzzy)).await?;
+        assert_eq!(fuzzy, vec![inserted[0].clone()]);
+
+        assert!(matches!(
+            Link::search(&mut conn, &search(" ", SearchMode::Fuzzy)).await,
+            Err(ListError::EmptyQuery)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_tags() -> Result<()> {
+        let tags = vec![
+            "Spring-Sale".to_owned(),
+            " team:growth ".to_owned(),
+            "spring-sale".to_owned(),
+        ];
+        assert_eq!(normalize_tags(&tags)?, vec!["spring-sale", "team:growth"]);
+
+        assert!(normalize_tags(&[String::new()]).is_err());
+        assert!(normalize_tags(&["two words".to_owned()]).is_err());
+        assert!(
+            normalize_tags(&(0..=MAX_TAGS).map(|n| n.to_string()).collect::<Vec<_>>()).is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metadata() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+        let slugs = Slugs::new(&crate::config::LinksConfig::default())?;
+
+        let new = NewLink {
+            destination: "https://www.google.com".to_owned(),
+            title: Some("Spring catalogue".to_owned()),
+            tags: vec!["Spring".to_owned(), "marketing".to_owned()],
+            ..NewLink::default()
+        };
+        let link = match Link::create(
+            &mut conn,
+            new,
+            &slugs,
+            DuplicatePolicy::Create,
+            &DestinationPolicy::default(),
+        )
+        .await?
+        {
+            Created::Inserted(link) | Created::Existing(link) => link,
+        };
+        assert_eq!(link.title.as_deref(), Some("Spring catalogue"));
+        assert_eq!(link.tags, vec!["marketing", "spring"]);
+        Link::insert(&mut conn, Link::new(&Url::parse("https://www.bing.com")?)).await?;
+
+        let query = LinkQuery {
+            tag: Some("Spring".to_owned()),
+            ..LinkQuery::default()
+        };
+        assert_eq!(
+            Link::list(&mut conn, &query).await?.links,
+            vec![link.clone()]
+        );
+
+        let search = LinkSearch {
+            q: "catalog".to_owned(),
+            ..LinkSearch::default()
+        };
+        assert_eq!(Link::search(&mut conn, &search).await?, vec![link.clone()]);
+
+        let update = LinkUpdate {
+            title: Some(String::new()),
+            description: Some("Seasonal offers".to_owned()),
+            tags: Some(vec!["summer".to_owned()]),
+            ..LinkUpdate::default()
+        };
+        let updated = Link::update(&mut conn, &link.hash, update, &DestinationPolicy::default())
+            .await?
+            .expect("link should exist");
+        assert_eq!(updated.title, None);
+        assert_eq!(updated.description.as_deref(), Some("Seasonal offers"));
+        assert_eq!(updated.tags, vec!["summer"]);
+        assert!(Link::list(&mut conn, &query).await?.links.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_reject_duplicate_destination() -> Result<()> {
+        use crate::api_keys::{ApiKey, Role};
+
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+        let slugs = Slugs::new(&crate::config::LinksConfig::default())?;
+        let policy = DestinationPolicy::default();
+        let editor = ApiKey::issue(&mut conn, "editor", Role::Editor)
+            .await?
+            .key
+            .id;
+        let new = NewLink {
+            destination: "https://www.google.com".to_owned(),
+            ..NewLink::default()
+        };
+        Link::create(
+            &mut conn,
+            new.clone(),
+            &slugs,
+            DuplicatePolicy::Reject,
+            &policy,
+        )
+        .await?;
+
+        // any live link to the destination is a conflict, however it differs
+        // and whoever created it
+        for different in [
+            NewLink {
+                title: Some("Search".to_owned()),
+                tags: vec!["search".to_owned()],
+                ..new.clone()
+            },
+            NewLink {
+                expires_at: Some(Utc::now() + chrono::Duration::days(1)),
+                max_visits: Some(3),
+                ..new.clone()
+            },
+            NewLink {
+                created_by: Some(Owner::ApiKey(editor)),
+                ..new.clone()
+            },
+        ] {
+            let rejected = Link::create(
+                &mut conn,
+                different,
+                &slugs,
+                DuplicatePolicy::Reject,
+                &policy,
+            )
+            .await;
+            assert!(matches!(rejected, Err(NewLinkError::DestinationTaken)));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_duplicate_with_metadata() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+        let slugs = Slugs::new(&crate::config::LinksConfig::default())?;
+        let policy = DestinationPolicy::default();
+        let new = NewLink {
+            destination: "https://www.google.com".to_owned(),
+            title: Some("Spring catalogue".to_owned()),
+            tags: vec!["spring".to_owned()],
+            ..NewLink::default()
+        };
+        let first = match Link::create(
+            &mut conn,
+            new.clone(),
+            &slugs,
+            DuplicatePolicy::Reuse,
+            &policy,
+        )
+        .await?
+        {
+            Created::Inserted(link) | Created::Existing(link) => link,
+        };
+
+        // tags are compared once normalized
+        let same = NewLink {
+            tags: vec!["Spring".to_owned()],
+            ..new.clone()
+        };
+        let reused = Link::create(&mut conn, same, &slugs, DuplicatePolicy::Reuse, &policy).await?;
+        assert_eq!(reused, Created::Existing(first.clone()));
+
+        for different in [
+            NewLink {
+                title: Some("Summer catalogue".to_owned()),
+                ..new.clone()
+            },
+            NewLink {
+                description: Some("Seasonal offers".to_owned()),
+                ..new.clone()
+            },
+            NewLink {
+                tags: vec!["summer".to_owned()],
+                ..new.clone()
+            },
+        ] {
+            let created = Link::create(
+                &mut conn,
+                different,
+                &slugs,
+                DuplicatePolicy::Reuse,
+                &policy,
+            )
+            .await?;
+            assert!(matches!(created, Created::Inserted(link) if link.id != first.id));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_created_by() -> Result<()> {
+        use crate::api_keys::{ApiKey, Role};
+
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+        let slugs = Slugs::new(&crate::config::LinksConfig::default())?;
+        let alice = ApiKey::issue(&mut conn, "alice", Role::Editor)
+            .await?
+            .key
+            .id;
+        let bob = ApiKey::issue(&mut conn, "bob", Role::Editor).await?.key.id;
+
+        let mut created = Vec::new();
+        let carol = Owner::Subject("carol@example.com".to_owned());
+        for created_by in [Owner::ApiKey(alice), Owner::ApiKey(bob), carol.clone()] {
+            let new = NewLink {
+                destination: "https://www.google.com".to_owned(),
+                created_by: Some(created_by),
+                ..NewLink::default()
+            };
+            // each caller gets its own link rather than reusing another caller's
+            match Link::create(
+                &mut conn,
+                new,
+                &slugs,
+                DuplicatePolicy::Reuse,
+                &DestinationPolicy::default(),
+            )
+            .await?
+            {
+                Created::Inserted(link) => created.push(link),
+                Created::Existing(link) => panic!("unexpectedly reused {:?}", link),
+            }
+        }
+
+        let query = LinkQuery {
+            created_by: Some(Owner::ApiKey(alice)),
+            ..LinkQuery::default()
+        };
+        assert_eq!(
+            Link::list(&mut conn, &query).await?.links,
+            vec![created[0].clone()]
+        );
+        let query = LinkQuery {
+            created_by: Some(carol.clone()),
+            ..LinkQuery::default()
+        };
+        assert_eq!(
+            Link::list(&mut conn, &query).await?.links,
+            vec![created[2].clone()]
+        );
+        assert_eq!(created[2].owner(), Some(carol));
+        assert_eq!(
+            Link::list(&mut conn, &LinkQuery::default())
+                .await?
+                .links
+                .len(),
+            3
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve() -> Result<()> {
+        use crate::config::DestinationsConfig;
+
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+        let policy = DestinationPolicy::new(&DestinationsConfig {
+            public_hosts: vec!["sho.rt".to_owned()],
+            ..DestinationsConfig::default()
+        })?;
+
+        let target = Link::insert(
+            &mut conn,
+            Link::new(&Url::parse("https://www.bing.com/?a=1")?),
+        )
+        .await?;
+        let mut chained = Link::new(&Url::parse(&format!("https://sho.rt/{}", target.hash))?);
+        chained.utm_source = Some("newsletter".to_owned());
+        chained.forward_query = true;
+        let (destination, hops) = chained.resolve(&mut conn, &policy, Some("b=2")).await?;
+        assert_eq!(
+            destination.as_str(),
+            "https://www.bing.com/?a=1&utm_source=newsletter&b=2"
+        );
+        assert_eq!(hops, vec![target]);
+
+        // another part of this service is left for the client to follow, with
+        // the same query parameters
+        let mut elsewhere = chained.clone();
+        elsewhere.destination = "https://sho.rt/missing?a=1".to_owned();
+        let (destination, hops) = elsewhere.resolve(&mut conn, &policy, Some("b=2")).await?;
+        assert_eq!(
+            destination.as_str(),
+            "https://sho.rt/missing?a=1&utm_source=newsletter&b=2"
+        );
+        assert!(hops.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_self_links() -> Result<()> {
+        use crate::config::DestinationsConfig;
+
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+        let slugs = Slugs::new(&crate::config::LinksConfig::default())?;
+        let mut config = DestinationsConfig {
+            public_hosts: vec!["sho.rt".to_owned()],
+            ..DestinationsConfig::default()
+        };
+        let policy = DestinationPolicy::new(&config)?;
+
+        let target =
+            Link::insert(&mut conn, Link::new(&Url::parse("https://www.bing.com")?)).await?;
+        let new = NewLink {
+            destination: format!("https://sho.rt/{}", target.hash),
+            ..NewLink::default()
+        };
+        let created = match Link::create(
+            &mut conn,
+            new.clone(),
+            &slugs,
+            DuplicatePolicy::Create,
+            &policy,
+        )
+        .await?
+        {
+            Created::Inserted(link) | Created::Existing(link) => link,
+        };
+        assert_eq!(created.destination, target.destination);
+
+        let missing = NewLink {
+            destination: "https://sho.rt/missing".to_owned(),
+            ..NewLink::default()
+        };
+        let result =
+            Link::create(&mut conn, missing, &slugs, DuplicatePolicy::Create, &policy).await;
+        assert!(matches!(result, Err(NewLinkError::SelfReference)));
+
+        // resolving would escape the limits of the target
+        let mut one_time = Link::new(&Url::parse("https://www.bing.com")?);
+        one_time.max_visits = Some(1);
+        one_time.remaining_visits = Some(1);
+        let mut expiring = Link::new(&Url::parse("https://www.bing.com")?);
+        expiring.expires_at = Some(Utc::now() + chrono::Duration::days(1));
+        for limited in [one_time, expiring] {
+            let limited = Link::insert(&mut conn, limited).await?;
+            let new = NewLink {
+                destination: format!("https://sho.rt/{}", limited.hash),
+                ..NewLink::default()
+            };
+            let result =
+                Link::create(&mut conn, new, &slugs, DuplicatePolicy::Create, &policy).await;
+            assert!(matches!(result, Err(NewLinkError::LimitedSelfReference)));
+        }
+
+        // a caller limited to their own links can't resolve anyone else's
+        let editor = Owner::Subject("editor@example.com".to_owned());
+        let result = Link::create(
+            &mut conn,
+            NewLink {
+                visible_to: Some(editor.clone()),
+                ..new.clone()
+            },
+            &slugs,
+            DuplicatePolicy::Create,
+            &policy,
+        )
+        .await;
+        assert!(matches!(result, Err(NewLinkError::SelfReference)));
+        let mut own = Link::new(&Url::parse("https://www.bing.com")?);
+        own.created_by_subject = Some("editor@example.com".to_owned());
+        let own = Link::insert(&mut conn, own).await?;
+        let result = Link::create(
+            &mut conn,
+            NewLink {
+                destination: format!("https://sho.rt/{}", own.hash),
+                visible_to: Some(editor),
+                ..NewLink::default()
+            },
+            &slugs,
+            DuplicatePolicy::Create,
+            &policy,
+        )
+        .await;
+        assert!(matches!(result, Ok(Created::Inserted(_))));
+
+        config.self_links = SelfLinkPolicy::Reject;
+        let rejecting = DestinationPolicy::new(&config)?;
+        let result =
+            Link::create(&mut conn, new, &slugs, DuplicatePolicy::Create, &rejecting).await;
+        assert!(matches!(result, Err(NewLinkError::SelfReference)));
+
+        // a loop can only be built by inserting links directly
+        let mut first = Link::new(&Url::parse("https://sho.rt/second")?);
+        first.hash = "first".to_owned();
+        let mut second = Link::new(&Url::parse("https://sho.rt/first")?);
+        second.hash = "second".to_owned();
+        Link::insert(&mut conn, first).await?;
+        Link::insert(&mut conn, second).await?;
+        let result =
+            Link::follow_self_links(&mut conn, Url::parse("https://sho.rt/first")?, &policy).await;
+        assert!(matches!(result, Err(NewLinkError::RedirectLoop)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_canonical_destinations() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+        let slugs = Slugs::new(&crate::config::LinksConfig::default())?;
+        let policy = DestinationPolicy::default();
+        let create = |destination: &str| NewLink {
+            destination: destination.to_owned(),
+            ..NewLink::default()
+        };
+
+        let first = match Link::create(
+            &mut conn,
+            create(" HTTPS://Example.COM:443/a/../b?"),
+            &slugs,
+            DuplicatePolicy::Reuse,
+            &policy,
+        )
+        .await?
+        {
+            Created::Inserted(link) | Created::Existing(link) => link,
+        };
+        assert_eq!(first.destination, "https://example.com/b");
+        assert_eq!(
+            first.original_destination,
+            " HTTPS://Example.COM:443/a/../b?"
+        );
+
+        let reused = Link::create(
+            &mut conn,
+            create("https://example.com/b"),
+            &slugs,
+            DuplicatePolicy::Reuse,
+            &policy,
+        )
+        .await?;
+        assert_eq!(reused, Created::Existing(first));
+
+        let tagged = NewLink {
+            utm_source: Some("newsletter".to_owned()),
+            ..create("https://example.com/b")
+        };
+        let created =
+            Link::create(&mut conn, tagged, &slugs, DuplicatePolicy::Reuse, &policy).await?;
+        assert!(matches!(created, Created::Inserted(_)));
 
-        assert_eq!(list, vec![inserted]);
+        let idn = Link::try_from(create("https://BÜCHER.example/"))?;
+        assert_eq!(idn.destination, "https://xn--bcher-kva.example/");
         Ok(())
     }
 }
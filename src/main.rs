@@ -1,5 +1,5 @@
-use anyhow::Result;
-use axum_rest_example::{config::AppConfig, server, telemetry};
+use anyhow::{bail, Result};
+use axum_rest_example::{commands, config::AppConfig, server, telemetry};
 use tracing::debug;
 
 #[tokio::main]
@@ -7,6 +7,17 @@ async fn main() -> Result<()> {
     let config = AppConfig::new()?;
     telemetry::init(&config)?;
     debug!(?config);
-    server::launch(&config).await?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None | Some("serve") => server::launch(&config).await?,
+        Some("purge") => commands::purge(&config).await?,
+        Some("keys") => commands::keys(&config, &args[1..]).await?,
+        Some(other) => bail!(
+            "unknown command `{}`, expected `serve`, `purge` or `keys`",
+            other
+        ),
+    }
+
     Ok(())
 }
@@ -0,0 +1,264 @@
+//! Minimal server-rendered HTML for browsers following shortened links
+//!
+//! API clients receive JSON instead, see [`wants_html`].
+
+use std::fs;
+
+use anyhow::{Context, Result};
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use url::Url;
+
+use crate::{
+    config::{NotFoundMode, RedirectsConfig},
+    links::LinkPreview,
+};
+
+/// Whether a request's `Accept` header prefers an HTML response, as sent by
+/// browsers navigating to a shortened link
+pub(crate) fn wants_html(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("text/html"))
+}
+
+/// Escapes text for safe interpolation into HTML content or attribute values
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Renders a complete HTML document around already-escaped `body` markup
+pub(crate) fn render(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+</head>
+<body>
+{body}
+</body>
+</html>
+"#,
+        title = escape(title),
+        body = body
+    )
+}
+
+/// Renders a page explaining why a shortened link could not be followed
+pub(crate) fn message(title: &str, message: &str) -> String {
+    render(
+        title,
+        &format!("<h1>{}</h1>\n<p>{}</p>", escape(title), escape(message)),
+    )
+}
+
+/// Renders a page showing where a shortened link leads, with a button which
+/// follows it by way of `continue_to`
+pub(crate) fn preview(preview: &LinkPreview, continue_to: &str) -> String {
+    let title = preview.title.as_deref().unwrap_or("Link preview");
+    let destination = match &preview.destination {
+        Some(destination) => format!(
+            "<p>This link leads to:</p>\n<p><code>{}</code></p>",
+            escape(destination)
+        ),
+        None => "<p>This link may only be visited a limited number of times, so where it \
+            leads is only shown by following it.</p>"
+            .to_owned(),
+    };
+    render(
+        title,
+        &format!(
+            r#"<h1>{title}</h1>
+{destination}
+<p>Created {created_at}</p>
+<p><a href="{continue_to}" role="button">Continue</a></p>"#,
+            title = escape(title),
+            destination = destination,
+            created_at = preview.created_at.format("%B %-d, %Y"),
+            continue_to = escape(continue_to),
+        ),
+    )
+}
+
+/// The response to a visit to a short link which does not exist, as
+/// configured by a [`RedirectsConfig`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum NotFoundPage {
+    /// `404 Not Found` with a JSON body, or an HTML page for browsers
+    Status,
+    /// A redirect to a landing page
+    Redirect(Url),
+    /// `404 Not Found` with a custom HTML page
+    Template(String),
+}
+
+impl NotFoundPage {
+    /// Resolves the configured [`NotFoundMode`], reading any template file
+    pub(crate) fn new(config: &RedirectsConfig) -> Result<Self> {
+        match config.not_found {
+            NotFoundMode::Status => Ok(Self::Status),
+            NotFoundMode::Redirect => config
+                .not_found_url
+                .clone()
+                .map(Self::Redirect)
+                .context("redirects.not_found_url is required in redirect mode"),
+            NotFoundMode::Template => {
+                let path = config
+                    .not_found_template
+                    .as_deref()
+                    .context("redirects.not_found_template is required in template mode")?;
+                let template = fs::read_to_string(path).with_context(|| {
+                    format!("could not read not found template {}", path.display())
+                })?;
+
+                Ok(Self::Template(template))
+            }
+        }
+    }
+
+    /// Responds to a visit to the unknown short link `slug`
+    pub(crate) fn respond(&self, headers: &HeaderMap, slug: &str) -> Response {
+        match self {
+            Self::Status if wants_html(headers) => {
+                let page = message("Link not found", "this link does not exist");
+                (StatusCode::NOT_FOUND, Html(page)).into_response()
+            }
+            Self::Status => (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "link not found" })),
+            )
+                .into_response(),
+            // the slug may still be created, so the redirect is never cached
+            Self::Redirect(url) => (
+                StatusCode::TEMPORARY_REDIRECT,
+                [
+                    (header::LOCATION, url.as_str()),
+                    (header::CACHE_CONTROL, "no-store"),
+                ],
+            )
+                .into_response(),
+            Self::Template(template) => {
+                let page = template.replace("{slug}", &escape(slug));
+                (StatusCode::NOT_FOUND, Html(page)).into_response()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::links::Link;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape(r#"<a href="x">Tom & 'Jerry'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#x27;Jerry&#x27;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn test_wants_html() {
+        let mut headers = HeaderMap::new();
+        assert!(!wants_html(&headers));
+
+        headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+        assert!(!wants_html(&headers));
+
+        headers.insert(
+            header::ACCEPT,
+            HeaderValue::from_static("text/html,application/xhtml+xml,*/*;q=0.8"),
+        );
+        assert!(wants_html(&headers));
+    }
+
+    #[tokio::test]
+    async fn test_not_found_page() {
+        let headers = HeaderMap::new();
+
+        let response = NotFoundPage::Status.respond(&headers, "abcde");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let landing = Url::parse("https://example.com/").unwrap();
+        let response = NotFoundPage::Redirect(landing).respond(&headers, "abcde");
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "https://example.com/");
+
+        let template = NotFoundPage::Template("<p>No {slug} here</p>".to_owned());
+        let response = template.respond(&headers, "<b>");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "<p>No &lt;b&gt; here</p>");
+
+        let config = RedirectsConfig {
+            not_found: NotFoundMode::Redirect,
+            ..RedirectsConfig::default()
+        };
+        assert!(NotFoundPage::new(&config).is_err());
+
+        let config = RedirectsConfig {
+            not_found: NotFoundMode::Template,
+            not_found_template: Some("missing.html".into()),
+            ..RedirectsConfig::default()
+        };
+        assert!(NotFoundPage::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_preview() {
+        let link = LinkPreview {
+            hash: "abcde".to_owned(),
+            destination: Some("https://example.com/?a=1&b=<2>".to_owned()),
+            title: Some("Spring sale".to_owned()),
+            created_at: chrono::TimeZone::ymd(&chrono::Utc, 2026, 3, 1).and_hms(12, 0, 0),
+            expires_at: None,
+        };
+
+        let page = preview(&link, "/abcde?utm_campaign=x&y=1");
+        assert!(page.contains("<h1>Spring sale</h1>"));
+        assert!(page.contains("<code>https://example.com/?a=1&amp;b=&lt;2&gt;</code>"));
+        assert!(page.contains("Created March 1, 2026"));
+        assert!(page.contains(r#"href="/abcde?utm_campaign=x&amp;y=1""#));
+    }
+
+    #[test]
+    fn test_preview_visit_limited() -> Result<()> {
+        let mut link = Link::new(&Url::parse("https://example.com/secret")?);
+        link.max_visits = Some(1);
+        link.remaining_visits = Some(1);
+
+        let link = link.preview();
+        assert_eq!(link.destination, None);
+        assert!(serde_json::to_value(&link)?.get("destination").is_none());
+
+        let page = preview(&link, "/abcde");
+        assert!(!page.contains("https://example.com/secret"));
+        assert!(page.contains("limited number of times"));
+        assert!(page.contains(r#"href="/abcde""#));
+        Ok(())
+    }
+}
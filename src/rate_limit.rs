@@ -0,0 +1,330 @@
+//! Per-client rate limiting with token buckets, as a [`tower`] layer
+//!
+//! Each client has a bucket holding up to `burst` tokens, which refills at
+//! `per_minute` tokens per minute. Every request takes a token, and a request
+//! finding the bucket empty is rejected with `429 Too Many Requests` and a
+//! `Retry-After` header. Responses also describe the client's bucket with
+//! `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers, as
+//! drafted by the IETF.
+//!
+//! Buckets are tracked with the generic cell rate algorithm, which stores
+//! only the moment each client's bucket will be full again. At most
+//! [`MAX_TRACKED_CLIENTS`] buckets are kept, in two generations: once the
+//! current generation is half full it replaces the previous one, forgetting
+//! the clients which were not seen since, so bookkeeping stays constant-time
+//! however many clients there are.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::{header, Extensions, HeaderMap, HeaderValue, Request, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::future::BoxFuture;
+use serde_json::json;
+use tower::{Layer, Service};
+use tracing::debug;
+
+use crate::config::RateLimitConfig;
+
+/// The most clients tracked at once, across both generations of [`Buckets`]
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+/// The state of a client's bucket after a request, see [`RateLimiter::check`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Decision {
+    /// the most tokens the bucket holds
+    limit: u32,
+    /// tokens left in the bucket
+    remaining: u32,
+    /// how long until the bucket is full again
+    reset: Duration,
+    /// how long until a token is available, when the request was rejected
+    retry_after: Option<Duration>,
+}
+
+impl Decision {
+    fn apply(self, headers: &mut HeaderMap) {
+        let mut insert = |name: &'static str, value: u64| {
+            headers.insert(name, HeaderValue::from(value));
+        };
+        insert("ratelimit-limit", self.limit.into());
+        insert("ratelimit-remaining", self.remaining.into());
+        insert("ratelimit-reset", whole_seconds(self.reset));
+    }
+}
+
+/// Rounds up to whole seconds, as `Retry-After` and `RateLimit-Reset` require
+fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// The moment each recently seen client's bucket will be full again
+#[derive(Debug, Default)]
+struct Buckets {
+    /// clients seen since the generations were last rotated
+    current: HashMap<String, Instant>,
+    /// clients seen during the generation before, which are forgotten at the
+    /// next rotation unless they are seen again
+    previous: HashMap<String, Instant>,
+}
+
+impl Buckets {
+    fn get(&self, key: &str) -> Option<Instant> {
+        self.current
+            .get(key)
+            .or_else(|| self.previous.get(key))
+            .copied()
+    }
+
+    /// Records `full_at` for `key` in the current generation, first rotating
+    /// the generations when the current one is full
+    fn insert(&mut self, key: &str, full_at: Instant) {
+        if self.current.len() >= MAX_TRACKED_CLIENTS / 2 && !self.current.contains_key(key) {
+            self.previous = std::mem::take(&mut self.current);
+        }
+        self.previous.remove(key);
+        self.current.insert(key.to_owned(), full_at);
+    }
+}
+
+/// Token buckets for every client subject to one [`RateLimitConfig`]
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Takes a token from the bucket of the client identified by `key`,
+    /// returning `None` when this limit is disabled
+    fn check(&self, key: &str, now: Instant) -> Option<Decision> {
+        if self.config.per_minute == 0 {
+            return None;
+        }
+        let limit = self.config.burst.max(1);
+        let interval = Duration::from_secs(60) / self.config.per_minute;
+        let capacity = interval * limit;
+
+        let mut buckets = self.buckets.lock().expect("rate limiter lock is poisoned");
+        let current = buckets.get(key).filter(|at| *at > now);
+        let used = current.map_or(Duration::ZERO, |at| at - now);
+        let tokens = |unused: Duration| {
+            u32::try_from(unused.as_nanos() / interval.as_nanos()).unwrap_or(u32::MAX)
+        };
+
+        if used + interval > capacity {
+            // keep the bucket of a client which is still being rejected
+            buckets.insert(key, now + used);
+            return Some(Decision {
+                limit,
+                remaining: 0,
+                reset: used,
+                retry_after: Some(used + interval - capacity),
+            });
+        }
+
+        let used = used + interval;
+        buckets.insert(key, now + used);
+        Some(Decision {
+            limit,
+            remaining: tokens(capacity - used),
+            reset: used,
+            retry_after: None,
+        })
+    }
+}
+
+/// Identifies the client a request counts against, such as its API key or IP address
+pub(crate) type ClientKey = fn(&Extensions, &HeaderMap) -> String;
+
+/// A [`Layer`] applying a [`RateLimiter`] to each client identified by a [`ClientKey`]
+#[derive(Clone)]
+pub(crate) struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+    key: ClientKey,
+}
+
+impl RateLimitLayer {
+    pub(crate) fn new(config: RateLimitConfig, key: ClientKey) -> Self {
+        Self {
+            limiter: Arc::new(RateLimiter::new(config)),
+            key,
+        }
+    }
+}
+
+impl fmt::Debug for RateLimitLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimitLayer")
+            .field("limiter", &self.limiter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+            key: self.key,
+        }
+    }
+}
+
+/// The [`Service`] produced by [`RateLimitLayer`]
+#[derive(Clone)]
+pub(crate) struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+    key: ClientKey,
+}
+
+impl<S: fmt::Debug> fmt::Debug for RateLimit<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimit")
+            .field("inner", &self.inner)
+            .field("limiter", &self.limiter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, B> Service<Request<B>> for RateLimit<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let key = (self.key)(req.extensions(), req.headers());
+        let decision = self.limiter.check(&key, Instant::now());
+
+        if let Some(
+            decision @ Decision {
+                retry_after: Some(retry_after),
+                ..
+            },
+        ) = decision
+        {
+            debug!(client = %key, "rate limit exceeded");
+            let mut response = (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, whole_seconds(retry_after))],
+                Json(json!({ "error": "too many requests" })),
+            )
+                .into_response();
+            decision.apply(response.headers_mut());
+            return Box::pin(async { Ok(response) });
+        }
+
+        // the clone may not be ready, so call the instance `poll_ready` was called on
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let mut response = inner.call(req).await?;
+            if let Some(decision) = decision {
+                decision.apply(response.headers_mut());
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            burst: 3,
+            per_minute: 60,
+        });
+        let start = Instant::now();
+
+        for remaining in [2, 1, 0] {
+            let decision = limiter.check("a", start).unwrap();
+            assert_eq!(decision.remaining, remaining);
+            assert_eq!(decision.retry_after, None);
+        }
+        let rejected = limiter.check("a", start).unwrap();
+        assert_eq!(rejected.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(rejected.reset, Duration::from_secs(3));
+
+        // other clients have their own buckets
+        assert_eq!(limiter.check("b", start).unwrap().remaining, 2);
+
+        // a token is refilled every second
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(limiter.check("a", later).unwrap().retry_after, None);
+        assert!(limiter.check("a", later).unwrap().retry_after.is_some());
+        assert_eq!(
+            limiter
+                .check("a", start + Duration::from_secs(60))
+                .unwrap()
+                .remaining,
+            2
+        );
+    }
+
+    #[test]
+    fn test_tracked_clients() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            burst: 1,
+            per_minute: 1,
+        });
+        let start = Instant::now();
+
+        limiter.check("active", start).unwrap();
+        for client in 0..MAX_TRACKED_CLIENTS {
+            limiter.check(&client.to_string(), start).unwrap();
+            // clients seen in every generation keep their bucket
+            if client % 1000 == 0 {
+                assert!(limiter
+                    .check("active", start)
+                    .unwrap()
+                    .retry_after
+                    .is_some());
+            }
+        }
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.current.len() + buckets.previous.len() <= MAX_TRACKED_CLIENTS);
+        // the earliest clients have been forgotten
+        assert_eq!(buckets.get("0"), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            burst: 1,
+            per_minute: 0,
+        });
+        assert_eq!(limiter.check("a", Instant::now()), None);
+    }
+}
@@ -1,30 +1,47 @@
 //! [`axum`]-specific logic for offering a REST API
 
 use crate::{
-    config::AppConfig,
+    api_keys::{ApiKey, Role, KEY_MARKER},
+    config::{AppConfig, DuplicatePolicy, HttpConfig, RedirectsConfig, VisitsConfig},
     db,
-    links::{Link, NewLink, NewLinkError},
+    destinations::DestinationPolicy,
+    export::ExportFormat,
+    jwt::JwtValidator,
+    links::{
+        Created, Link, LinkQuery, LinkSearch, LinkUpdate, ListError, NewLink, NewLinkError, Owner,
+        SlugAvailability,
+    },
+    pages::{self, NotFoundPage},
+    rate_limit::RateLimitLayer,
+    slugs::Slugs,
+    visits::{self, Bucket, LinkStats, NewVisit, VisitRecorder},
 };
 use anyhow::Result;
 use axum::{
-    body::BoxBody,
-    extract::{self, Extension, Json},
-    http::{Request, StatusCode},
-    response::{IntoResponse, Redirect, Response},
-    routing::{get, post},
+    async_trait,
+    body::{boxed, BoxBody},
+    extract::{self, ConnectInfo, Extension, FromRequest, Json, OriginalUri, Query, RequestParts},
+    http::{header, Extensions, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::from_extractor,
+    response::{Html, IntoResponse, Response},
+    routing::{get, patch, post},
     Router, Server,
 };
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use hyper::Body;
+use serde::Deserialize;
 use serde_json::json;
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
 use std::{
-    convert::TryInto,
+    marker::PhantomData,
     net::{IpAddr, SocketAddr},
+    sync::Arc,
     time::Duration,
 };
 use tokio::signal::unix::{signal, SignalKind};
 use tower_http::trace::TraceLayer;
-use tracing::{debug_span, field, info, instrument, span, Span};
+use tracing::{debug, debug_span, error, field, info, instrument, span, warn, Instrument, Span};
 
 /// Wrapper Error enum used to provide a consistent [`IntoResponse`] target for
 /// request handlers that return inner domain Error types.
@@ -32,17 +49,43 @@ use tracing::{debug_span, field, info, instrument, span, Span};
 enum AppError {
     #[error("error creating link")]
     NewLinkError(#[from] NewLinkError),
+    #[error("error listing links")]
+    ListError(#[from] ListError),
     #[error("database error")]
     SqlError(#[from] sqlx::Error),
+    /// a database error which has already been logged
+    #[error("database error")]
+    DatabaseError,
+    #[error("link not found")]
+    NotFound,
+    #[error(transparent)]
+    AuthError(#[from] AuthError),
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let (status, message) = match self {
-            AppError::NewLinkError(_) => {
-                (StatusCode::UNPROCESSABLE_ENTITY, "could not create link")
+            AppError::NewLinkError(NewLinkError::DatabaseError | NewLinkError::HashesExhausted) => {
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "could not create link".to_owned(),
+                )
+            }
+            AppError::NewLinkError(
+                err @ (NewLinkError::SlugTaken | NewLinkError::DestinationTaken),
+            ) => (StatusCode::CONFLICT, err.to_string()),
+            AppError::NewLinkError(err) => (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()),
+            AppError::ListError(err @ (ListError::InvalidCursor | ListError::EmptyQuery)) => {
+                (StatusCode::BAD_REQUEST, err.to_string())
             }
-            AppError::SqlError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "database error"),
+            AppError::ListError(ListError::Database(_))
+            | AppError::SqlError(_)
+            | AppError::DatabaseError => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "database error".to_owned(),
+            ),
+            err @ AppError::NotFound => (StatusCode::NOT_FOUND, err.to_string()),
+            AppError::AuthError(err) => return err.into_response(),
         };
 
         let body = Json(json!({ "error": message }));
@@ -51,6 +94,167 @@ impl IntoResponse for AppError {
     }
 }
 
+/// Reasons a request to the `/v1` API could not be authenticated or authorized
+#[derive(Debug, thiserror::Error)]
+enum AuthError {
+    #[error("missing bearer token")]
+    MissingCredentials,
+    #[error("invalid bearer token")]
+    InvalidCredentials,
+    #[error("these credentials are not permitted to perform this action")]
+    Forbidden,
+    #[error("database error")]
+    SqlError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            AuthError::MissingCredentials | AuthError::InvalidCredentials => {
+                StatusCode::UNAUTHORIZED
+            }
+            AuthError::Forbidden => StatusCode::FORBIDDEN,
+            AuthError::SqlError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = Json(json!({ "error": self.to_string() }));
+
+        if status == StatusCode::UNAUTHORIZED {
+            (status, [(header::WWW_AUTHENTICATE, "Bearer")], body).into_response()
+        } else {
+            (status, body).into_response()
+        }
+    }
+}
+
+/// The authenticated caller of a `/v1` API request
+///
+/// Extracting a `Caller` authenticates the request's `Authorization: Bearer`
+/// token as an [`ApiKey`] or, when a [`JwtValidator`] is configured, as a JWT
+/// whose roles claim grants a [`Role`]. Every `/v1` route requires this via
+/// [`from_extractor`], which caches the `Caller` in the request's extensions,
+/// so handlers may also extract it without authenticating twice.
+#[derive(Clone, Debug)]
+struct Caller {
+    /// the authenticating [`ApiKey`] or JWT subject, recorded on created `Link`s
+    principal: Owner,
+    role: Role,
+}
+
+impl Caller {
+    /// The creator whose `Link`s this caller is limited to, if any
+    ///
+    /// Editors only see and manage the `Link`s they created, while admins and
+    /// viewers see every `Link`.
+    fn owner(&self) -> Option<Owner> {
+        (self.role == Role::Editor).then(|| self.principal.clone())
+    }
+
+    /// Checks that this caller may see or manage the `Link` with a given
+    /// `hash`, including a soft-deleted one
+    ///
+    /// Another owner's `Link` is reported as `404 Not Found`, so that editors
+    /// can't learn which hashes are in use by others. A missing `Link` is
+    /// allowed here, leaving the handler to respond the same way.
+    async fn authorize_link(&self, conn: &mut PgConnection, hash: &str) -> Result<(), AppError> {
+        if let Some(owner) = self.owner() {
+            let link = Link::get_by_hash_including_deleted(conn, hash).await?;
+            if link.map_or(false, |link| link.owner() != Some(owner)) {
+                return Err(AppError::NotFound);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Caller {
+    type Rejection = AuthError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        if let Some(caller) = req.extensions().get::<Self>() {
+            return Ok(caller.clone());
+        }
+
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_owned())
+            .ok_or(AuthError::MissingCredentials)?;
+
+        let caller = if token.starts_with(KEY_MARKER) {
+            let Extension(db) = Extension::<PgPool>::from_request(req)
+                .await
+                .expect("database pool extension is missing");
+            let mut conn = db.acquire().await?;
+            let key = ApiKey::authenticate(&mut conn, &token)
+                .await?
+                .ok_or(AuthError::InvalidCredentials)?;
+
+            Self {
+                principal: Owner::ApiKey(key.id),
+                role: key.role,
+            }
+        } else {
+            let Extension(jwt) = Extension::<Option<Arc<JwtValidator>>>::from_request(req)
+                .await
+                .expect("JWT validator extension is missing");
+            let identity = jwt
+                .ok_or(AuthError::InvalidCredentials)?
+                .validate(&token)
+                .map_err(|err| {
+                    debug!(error = %err, "rejected JWT");
+                    AuthError::InvalidCredentials
+                })?;
+
+            Self {
+                principal: Owner::Subject(identity.subject),
+                role: identity.role.ok_or(AuthError::Forbidden)?,
+            }
+        };
+        req.extensions_mut().insert(caller.clone());
+        Ok(caller)
+    }
+}
+
+/// A kind of action which only some [`Role`]s may perform, for use with [`Authorized`]
+trait Permission {
+    fn allows(role: Role) -> bool;
+}
+
+/// Creating, changing, deleting and restoring `Link`s
+#[derive(Debug)]
+struct ManageLinks;
+
+impl Permission for ManageLinks {
+    fn allows(role: Role) -> bool {
+        matches!(role, Role::Admin | Role::Editor)
+    }
+}
+
+/// A [`Caller`] whose role grants the [`Permission`] `P`
+///
+/// Extracting it rejects other callers with `403 Forbidden`.
+#[derive(Debug)]
+struct Authorized<P>(Caller, PhantomData<P>);
+
+#[async_trait]
+impl<B: Send, P: Permission> FromRequest<B> for Authorized<P> {
+    type Rejection = AuthError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let caller = Caller::from_request(req).await?;
+
+        if P::allows(caller.role) {
+            Ok(Self(caller, PhantomData))
+        } else {
+            Err(AuthError::Forbidden)
+        }
+    }
+}
+
 /// GET handler for health requests by an application platform
 ///
 /// Intended for use in environments such as Amazon ECS or Kubernetes which want
@@ -66,46 +270,565 @@ async fn health_endpoint() -> &'static str {
 /// Extracts a [`NewLink`] from the request body as a JSON payload, and if
 /// valid, generates and inserts a [`Link`] into the database. Returns the
 /// inserted `Link` as the response body.
-#[instrument(skip(db))]
+///
+/// Depending on the configured [`DuplicatePolicy`], a destination which was
+/// already shortened may instead return the existing `Link` with `200 OK`.
+#[instrument(skip(db, slugs, policy, redirects))]
 async fn create_link(
     db: Extension<PgPool>,
-    Json(payload): Json<NewLink>,
+    slugs: Extension<Arc<Slugs>>,
+    Extension(duplicates): Extension<DuplicatePolicy>,
+    policy: Extension<Arc<DestinationPolicy>>,
+    Extension(redirects): Extension<RedirectsConfig>,
+    Authorized(caller, _): Authorized<ManageLinks>,
+    Json(mut payload): Json<NewLink>,
 ) -> Result<(StatusCode, Json<Link>), AppError> {
-    let link = payload.try_into()?;
+    let mut conn = db.acquire().await?;
+    payload.visible_to = caller.owner();
+    payload.created_by = Some(caller.principal);
+    payload.redirect_type.get_or_insert(redirects.default_type);
+
+    match Link::create(&mut conn, payload, &slugs, duplicates, &policy).await? {
+        Created::Inserted(link) => Ok((StatusCode::CREATED, link.into())),
+        Created::Existing(link) => Ok((StatusCode::OK, link.into())),
+    }
+}
 
+/// PATCH handler for changing an existing [`Link`]
+///
+/// Extracts a [`LinkUpdate`] from the request body as a JSON payload, and if
+/// valid, applies it to the `Link` with the given `hash`. Returns the updated
+/// `Link` as the response body, so that previously shared or printed short
+/// URLs follow the change.
+#[instrument(skip(db, policy))]
+async fn update_link(
+    db: Extension<PgPool>,
+    policy: Extension<Arc<DestinationPolicy>>,
+    Authorized(caller, _): Authorized<ManageLinks>,
+    extract::Path(hash): extract::Path<String>,
+    Json(mut payload): Json<LinkUpdate>,
+) -> Result<Json<Link>, AppError> {
     let mut conn = db.acquire().await?;
-    let inserted = Link::insert(&mut conn, link).await?;
+    caller.authorize_link(&mut conn, &hash).await?;
+    payload.visible_to = caller.owner();
 
-    Ok((StatusCode::CREATED, inserted.into()))
+    Link::update(&mut conn, &hash, payload, &policy)
+        .await?
+        .map(Json)
+        .ok_or(AppError::NotFound)
 }
 
-/// GET handler which lists all previously recorded [`Link`]s without any limits
+/// DELETE handler which soft-deletes an existing [`Link`]
 ///
-/// Returns a static ordering as determined by [`Link::list`].
+/// The `Link` stops redirecting immediately, but may be restored via
+/// [`restore_link`] until it is purged after the configured retention window.
 #[instrument(skip(db))]
-async fn list_links(db: Extension<PgPool>) -> Result<Json<Vec<Link>>, AppError> {
+async fn delete_link(
+    db: Extension<PgPool>,
+    Authorized(caller, _): Authorized<ManageLinks>,
+    extract::Path(hash): extract::Path<String>,
+) -> Result<StatusCode, AppError> {
     let mut conn = db.acquire().await?;
-    if let Ok(links) = Link::list(&mut conn).await {
-        Ok(links.into())
+    caller.authorize_link(&mut conn, &hash).await?;
+
+    if Link::delete(&mut conn, &hash).await? {
+        Ok(StatusCode::NO_CONTENT)
     } else {
-        Ok(Json(vec![]))
+        Err(AppError::NotFound)
     }
 }
 
-/// GET handler which fetches a [`Link`] and redirects to its `destination` URL
+/// POST handler which restores a previously soft-deleted [`Link`]
+#[instrument(skip(db))]
+async fn restore_link(
+    db: Extension<PgPool>,
+    Authorized(caller, _): Authorized<ManageLinks>,
+    extract::Path(hash): extract::Path<String>,
+) -> Result<Json<Link>, AppError> {
+    let mut conn = db.acquire().await?;
+    caller.authorize_link(&mut conn, &hash).await?;
+
+    Link::restore(&mut conn, &hash)
+        .await?
+        .map(Json)
+        .ok_or(AppError::NotFound)
+}
+
+/// GET handler which lists one page of previously recorded [`Link`]s
 ///
-/// Redirects to own `/` if no matching `hash` is found.
+/// Filtering, ordering and pagination are controlled by [`LinkQuery`]
+/// parameters. When another page follows, its cursor is returned as
+/// `next_cursor` in the body and as a `rel="next"` `Link` header. Editors
+/// only see the `Link`s they created.
 #[instrument(skip(db))]
-async fn visit_link(
+async fn list_links(
+    db: Extension<PgPool>,
+    caller: Caller,
+    OriginalUri(uri): OriginalUri,
+    Query(mut query): Query<LinkQuery>,
+) -> Result<Response, AppError> {
+    let mut conn = db.acquire().await?;
+    query.created_by = caller.owner();
+    let page = Link::list(&mut conn, &query).await?;
+
+    let mut headers = HeaderMap::new();
+    if let Some(cursor) = &page.next_cursor {
+        let params = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(
+                url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+                    .filter(|(key, _)| key != "cursor"),
+            )
+            .append_pair("cursor", cursor)
+            .finish();
+        let link = format!("<{}?{}>; rel=\"next\"", uri.path(), params);
+        if let Ok(value) = HeaderValue::from_str(&link) {
+            headers.insert(header::LINK, value);
+        }
+    }
+
+    Ok((headers, Json(page)).into_response())
+}
+
+/// GET handler which searches [`Link`]s by destination, most relevant first
+///
+/// See [`LinkSearch`] for the accepted parameters. Editors only find the
+/// `Link`s they created.
+#[instrument(skip(db))]
+async fn search_links(
     db: Extension<PgPool>,
+    caller: Caller,
+    Query(mut search): Query<LinkSearch>,
+) -> Result<Json<Vec<Link>>, AppError> {
+    let mut conn = db.acquire().await?;
+    search.created_by = caller.owner();
+    let links = Link::search(&mut conn, &search).await?;
+
+    Ok(links.into())
+}
+
+/// Query parameters accepted by [`export_links`]
+#[derive(Debug, Default, Deserialize)]
+struct ExportParams {
+    #[serde(default)]
+    format: ExportFormat,
+    /// Whether to include `Link`s which have expired, defaulting to `false`
+    #[serde(default)]
+    include_expired: bool,
+}
+
+/// Approximate number of bytes buffered before being sent as a chunk of an export
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// GET handler which exports every [`Link`] as NDJSON or CSV
+///
+/// Rows are streamed from the database into a chunked response body by a
+/// background task, so memory use stays constant regardless of how many
+/// `Link`s are exported. A database error part-way through aborts the body,
+/// which clients observe as a truncated response rather than a partial export
+/// that looks complete. Editors only export the `Link`s they created.
+#[instrument(skip(db))]
+async fn export_links(
+    db: Extension<PgPool>,
+    caller: Caller,
+    Query(params): Query<ExportParams>,
+) -> Result<Response, AppError> {
+    let mut conn = db.acquire().await?;
+    let owner = caller.owner();
+    let (mut sender, body) = Body::channel();
+    let format = params.format;
+
+    tokio::spawn(
+        async move {
+            let mut buf = Vec::with_capacity(EXPORT_CHUNK_SIZE);
+            format.write_header(&mut buf);
+
+            let mut links = Link::export(&mut conn, params.include_expired, owner.as_ref());
+            while let Some(link) = links.next().await {
+                let written = link
+                    .map_err(anyhow::Error::from)
+                    .and_then(|link| Ok(format.write_row(&mut buf, &link)?));
+                if let Err(err) = written {
+                    warn!(error = %err, "aborting link export");
+                    sender.abort();
+                    return;
+                }
+
+                if buf.len() >= EXPORT_CHUNK_SIZE {
+                    let chunk = std::mem::replace(&mut buf, Vec::with_capacity(EXPORT_CHUNK_SIZE));
+                    if sender.send_data(chunk.into()).await.is_err() {
+                        // the client has gone away
+                        return;
+                    }
+                }
+            }
+
+            if !buf.is_empty() {
+                sender.send_data(buf.into()).await.ok();
+            }
+        }
+        .in_current_span(),
+    );
+
+    let headers = [
+        (header::CONTENT_TYPE, format.content_type().to_owned()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", format.file_name()),
+        ),
+    ];
+
+    Ok((headers, boxed(body)).into_response())
+}
+
+/// Query parameters accepted by [`link_stats`]
+#[derive(Debug, Default, Deserialize)]
+struct StatsParams {
+    /// Width of each time bucket in the series, defaulting to `day`
+    #[serde(default)]
+    bucket: Bucket,
+    /// Only include visits from this moment onwards in the series
+    #[serde(default)]
+    since: Option<DateTime<Utc>>,
+}
+
+/// GET handler which reports visit statistics for an existing [`Link`]
+#[instrument(skip(db))]
+async fn link_stats(
+    db: Extension<PgPool>,
+    caller: Caller,
     extract::Path(hash): extract::Path<String>,
-) -> Result<Redirect, AppError> {
+    Query(params): Query<StatsParams>,
+) -> Result<Json<LinkStats>, AppError> {
     let mut conn = db.acquire().await?;
+    caller.authorize_link(&mut conn, &hash).await?;
+    let link = Link::get_by_hash(&mut conn, &hash)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let stats = LinkStats::for_link(&mut conn, &link, params.bucket, params.since).await?;
 
-    Link::get_by_hash(&mut conn, &hash).await?.map_or_else(
-        || Ok(Redirect::temporary("/")),
-        |link| Ok(Redirect::temporary(&link.destination)),
+    Ok(stats.into())
+}
+
+/// GET handler which reports whether a vanity slug may be requested for a new [`Link`]
+///
+/// Invalid slugs are reported as unavailable along with a `reason`, rather
+/// than as an error response, so that clients can use this for form validation.
+#[instrument(skip(db))]
+async fn slug_availability(
+    db: Extension<PgPool>,
+    extract::Path(slug): extract::Path<String>,
+) -> Result<Json<SlugAvailability>, AppError> {
+    let mut conn = db.acquire().await?;
+    let availability = Link::slug_availability(&mut conn, &slug).await?;
+
+    Ok(availability.into())
+}
+
+/// GET handler which fetches a [`Link`] and redirects to its `destination` URL
+///
+/// Responds as configured by the [`NotFoundPage`] if no matching `hash` is
+/// found, and with `410 Gone` instead of redirecting once the `Link` has been
+/// deleted, has expired, or has used up its limited visits. The `outcome` of
+/// each visit is recorded on its span, which keeps unknown slugs apart from
+/// database errors.
+///
+/// A `destination` which is another of this service's short links is
+/// followed here, redirecting straight to wherever the chain leads, while a
+/// chain longer than the configured `max_redirect_hops` is refused with
+/// `508 Loop Detected`. The visit counts against the limited visits of every
+/// `Link` in the chain.
+///
+/// The query string of the request is merged into the destination as
+/// described by [`Link::redirect_url`].
+///
+/// Visitors are redirected with the `Link`'s
+/// [`RedirectType`](crate::config::RedirectType). Permanent redirects may be
+/// cached by browsers and CDNs as described by [`Link::cache_max_age`], while
+/// temporary redirects are never cached, so that every visit is counted.
+///
+/// Each successful redirect is handed to the [`VisitRecorder`] for
+/// [`link_stats`], which writes it to the database in the background.
+///
+/// A request for `/:slug+` or `/:slug?preview=1` is answered by
+/// [`preview_link`] instead, without counting as a visit.
+#[instrument(
+    skip(db, http, redirects, not_found, visits, policy, recorder, headers, peer),
+    fields(outcome = field::Empty)
+)]
+#[allow(clippy::too_many_arguments)]
+async fn visit_link(
+    db: Extension<PgPool>,
+    Extension(http): Extension<HttpConfig>,
+    Extension(redirects): Extension<RedirectsConfig>,
+    not_found: Extension<Arc<NotFoundPage>>,
+    Extension(visits): Extension<VisitsConfig>,
+    policy: Extension<Arc<DestinationPolicy>>,
+    recorder: Extension<VisitRecorder>,
+    headers: HeaderMap,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    extract::Path(hash): extract::Path<String>,
+    extract::RawQuery(query): extract::RawQuery,
+) -> Result<Response, AppError> {
+    let span = Span::current();
+    let mut conn = db.acquire().await.map_err(lookup_failed)?;
+
+    if let Some((hash, query)) = preview_request(&hash, query.as_deref()) {
+        return preview_link(&mut conn, &not_found, &headers, &hash, query.as_deref()).await;
+    }
+
+    let link = match Link::get_by_hash_including_deleted(&mut conn, &hash)
+        .await
+        .map_err(lookup_failed)?
+    {
+        None => {
+            span.record("outcome", &"not_found");
+            debug!("no link has this hash");
+            return Ok(not_found.respond(&headers, &hash));
+        }
+        Some(link) if link.is_deleted() => {
+            span.record("outcome", &"deleted");
+            return Ok(unavailable(
+                &headers,
+                StatusCode::GONE,
+                "this link has been deleted",
+            ));
+        }
+        Some(link) if link.is_expired() => {
+            span.record("outcome", &"expired");
+            return Ok(unavailable(
+                &headers,
+                StatusCode::GONE,
+                "this link has expired",
+            ));
+        }
+        Some(link) => link,
+    };
+
+    let (destination, hops) = match link.resolve(&mut conn, &policy, query.as_deref()).await {
+        Ok(resolved) => resolved,
+        Err(NewLinkError::RedirectLoop) => {
+            span.record("outcome", &"redirect_loop");
+            return Ok(unavailable(
+                &headers,
+                StatusCode::LOOP_DETECTED,
+                "this link redirects through too many short links",
+            ));
+        }
+        Err(NewLinkError::DatabaseError) => return Err(follow_failed()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut chain = vec![&link];
+    chain.extend(&hops);
+    if !Link::claim_visits(&mut conn, &chain).await? {
+        span.record("outcome", &"expired");
+        return Ok(unavailable(
+            &headers,
+            StatusCode::GONE,
+            "this link has expired",
+        ));
+    }
+
+    let mut client_ip = client_ip(&headers, peer, http.trust_forwarded_for);
+    if visits.anonymize_ip {
+        client_ip = visits::anonymize_ip(client_ip);
+    }
+
+    recorder.record(NewVisit {
+        link_id: link.id,
+        visited_at: Utc::now(),
+        referrer: header_string(&headers, &header::REFERER),
+        user_agent: header_string(&headers, &header::USER_AGENT),
+        client_ip: Some(client_ip),
+    });
+
+    // a cached redirect must not outlive, or skip the visits of, any `Link` in the chain
+    let max_age = link
+        .cache_max_age(redirects.permanent_max_age_seconds)
+        .filter(|_| {
+            hops.iter()
+                .all(|hop| hop.expires_at.is_none() && hop.max_visits.is_none())
+        });
+    let cache_control = match max_age {
+        Some(max_age) => format!("public, max-age={}", max_age),
+        None => "no-store".to_owned(),
+    };
+    let status = StatusCode::from_u16(link.redirect_type().code())
+        .expect("redirect types are valid status codes");
+    span.record("outcome", &"redirected");
+
+    Ok((
+        status,
+        [
+            (header::LOCATION, destination.to_string()),
+            (header::CACHE_CONTROL, cache_control),
+        ],
     )
+        .into_response())
+}
+
+/// Responds with where the [`Link`] with a given `hash` leads, as an HTML
+/// page with a button continuing to the `Link` for browsers, or as JSON for
+/// API clients
+///
+/// A deleted or expired `Link` is answered with `410 Gone`, as by
+/// [`visit_link`]. `query` is kept for continuing, so that it is still
+/// forwarded to the destination. The destination of a `Link` with limited
+/// visits is left out, so that previewing can't stand in for using up a visit.
+async fn preview_link(
+    conn: &mut PgConnection,
+    not_found: &NotFoundPage,
+    headers: &HeaderMap,
+    hash: &str,
+    query: Option<&str>,
+) -> Result<Response, AppError> {
+    let span = Span::current();
+    let link = match Link::get_by_hash_including_deleted(conn, hash)
+        .await
+        .map_err(lookup_failed)?
+    {
+        None => {
+            span.record("outcome", &"not_found");
+            debug!("no link has this hash");
+            return Ok(not_found.respond(headers, hash));
+        }
+        Some(link) if link.is_deleted() => {
+            span.record("outcome", &"deleted");
+            return Ok(unavailable(
+                headers,
+                StatusCode::GONE,
+                "this link has been deleted",
+            ));
+        }
+        Some(link) if link.is_expired() => {
+            span.record("outcome", &"expired");
+            return Ok(unavailable(
+                headers,
+                StatusCode::GONE,
+                "this link has expired",
+            ));
+        }
+        Some(link) => link,
+    };
+
+    span.record("outcome", &"previewed");
+    let preview = link.preview();
+    if pages::wants_html(headers) {
+        let continue_to = match query {
+            Some(query) => format!("/{}?{}", link.hash, query),
+            None => format!("/{}", link.hash),
+        };
+        Ok(Html(pages::preview(&preview, &continue_to)).into_response())
+    } else {
+        Ok(Json(preview).into_response())
+    }
+}
+
+/// The `hash` of a [`Link`] to preview rather than follow, when requested as
+/// `/:slug+` or `/:slug?preview=1`, along with the rest of the query
+fn preview_request(hash: &str, query: Option<&str>) -> Option<(String, Option<String>)> {
+    let is_preview = |param: &&str| {
+        url::form_urlencoded::parse(param.as_bytes())
+            .next()
+            .map_or(false, |(name, value)| {
+                name == "preview" && (value == "1" || value == "true")
+            })
+    };
+    let params: Vec<&str> = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .collect();
+
+    let trimmed = hash.strip_suffix('+');
+    if trimmed.is_none() && !params.iter().any(is_preview) {
+        return None;
+    }
+
+    let rest: Vec<&str> = params
+        .into_iter()
+        .filter(|param| !is_preview(param))
+        .collect();
+    let rest = Some(rest.join("&")).filter(|rest| !rest.is_empty());
+    Some((trimmed.unwrap_or(hash).to_owned(), rest))
+}
+
+/// Logs a database error while looking up the [`Link`] for [`visit_link`],
+/// which must not be mistaken for an unknown slug
+fn lookup_failed(err: sqlx::Error) -> AppError {
+    Span::current().record("outcome", &"database_error");
+    error!(error = %err, "could not look up link");
+    err.into()
+}
+
+/// Logs a database error while following the short links a [`Link`] for
+/// [`visit_link`] redirects through, which must not be mistaken for a
+/// destination elsewhere on this service
+fn follow_failed() -> AppError {
+    Span::current().record("outcome", &"database_error");
+    error!("could not look up a short link this link redirects through");
+    AppError::DatabaseError
+}
+
+/// Determines the IP address of the client making a request, preferring the
+/// last address in `X-Forwarded-For` when the deployment is configured to
+/// trust it, and otherwise using the connected peer's address
+///
+/// Only the last address was appended by the trusted reverse proxy itself;
+/// any earlier ones were supplied by the client and may be spoofed.
+fn client_ip(headers: &HeaderMap, peer: SocketAddr, trust_forwarded_for: bool) -> IpAddr {
+    let forwarded_for = header::HeaderName::from_static("x-forwarded-for");
+
+    trust_forwarded_for
+        .then(|| headers.get_all(&forwarded_for).iter().next_back())
+        .flatten()
+        .and_then(|value| value.to_str().ok()?.rsplit(',').next()?.trim().parse().ok())
+        .unwrap_or_else(|| peer.ip())
+}
+
+/// Identifies the client a request counts against for rate limiting: the
+/// authenticated [`Caller`] on `/v1` routes, or otherwise its IP address
+fn rate_limit_key(extensions: &Extensions, headers: &HeaderMap) -> String {
+    if let Some(caller) = extensions.get::<Caller>() {
+        return match &caller.principal {
+            Owner::ApiKey(id) => format!("key:{}", id),
+            Owner::Subject(subject) => format!("sub:{}", subject),
+        };
+    }
+
+    ip_rate_limit_key(extensions, headers)
+}
+
+/// Identifies the client a request counts against for rate limiting by its
+/// IP address alone, whether or not it has been authenticated
+fn ip_rate_limit_key(extensions: &Extensions, headers: &HeaderMap) -> String {
+    let trust_forwarded_for = extensions
+        .get::<HttpConfig>()
+        .map_or(false, |http| http.trust_forwarded_for);
+    match extensions.get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(peer)) => format!("ip:{}", client_ip(headers, *peer, trust_forwarded_for)),
+        None => "ip:unknown".to_owned(),
+    }
+}
+
+/// Reads a header as a `String`, ignoring values which are not valid UTF-8
+fn header_string(headers: &HeaderMap, name: &header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(ToOwned::to_owned)
+}
+
+/// Builds an error response, such as `410 Gone`, for a [`Link`] which may
+/// not be followed, as an HTML page for browsers or as JSON for API clients
+fn unavailable(headers: &HeaderMap, status: StatusCode, message: &str) -> Response {
+    if pages::wants_html(headers) {
+        let page = pages::message("Link unavailable", message);
+        (status, Html(page)).into_response()
+    } else {
+        (status, Json(json!({ "error": message }))).into_response()
+    }
 }
 
 /// Internal helper for [`tower_http::trace::TraceLayer`] to create
@@ -155,19 +878,60 @@ fn emit_response_trace_with_id(response: &Response<BoxBody>, latency: Duration,
 /// [`tokio::signal`]-based task to listen for OS kill signals to allow
 /// in-flight requests to finish first, via
 /// [`axum::Server::with_graceful_shutdown`]. Currently also comprehensively
-/// defines all HTTP routes.
+/// defines all HTTP routes, where every `/v1` route requires an authenticated
+/// [`Caller`] while redirects stay public, and creating, listing and following
+/// links are rate limited per client. Unauthenticated `/v1` requests are rate
+/// limited per IP address too.
 pub async fn launch(config: &AppConfig) -> Result<()> {
     let root_span = span!(tracing::Level::TRACE, "app_start");
     let _enter = root_span.enter();
 
     let pool = db::new_pool(config).await?;
+    let slugs = Arc::new(Slugs::new(&config.links)?);
+    let policy = Arc::new(DestinationPolicy::new(&config.destinations)?);
+    let not_found = Arc::new(NotFoundPage::new(&config.redirects)?);
+    let (recorder, flusher) = VisitRecorder::spawn(pool.clone(), &config.visits);
+    let jwt = JwtValidator::load(&config.jwt).await?.map(Arc::new);
+    let jwks_refresher = jwt.clone().map(JwtValidator::spawn_refresh);
+
+    // listing, searching and exporting share one bucket per client
+    let create_limit = RateLimitLayer::new(config.http.create_rate_limit, rate_limit_key);
+    let list_limit = RateLimitLayer::new(config.http.list_rate_limit, rate_limit_key);
+    let redirect_limit = RateLimitLayer::new(config.http.redirect_rate_limit, rate_limit_key);
+    let api_limit = RateLimitLayer::new(config.http.api_rate_limit, ip_rate_limit_key);
+
+    // rate limits are applied per route, inside the `Caller` they are keyed by,
+    // while every `/v1` request also counts against its IP address before it
+    // is authenticated
+    let api_routes = Router::new()
+        .route("/v1/link", post(create_link).layer(create_limit))
+        .route("/v1/links", get(list_links).layer(list_limit.clone()))
+        .route(
+            "/v1/links/export",
+            get(export_links).layer(list_limit.clone()),
+        )
+        .route("/v1/links/search", get(search_links).layer(list_limit))
+        .route("/v1/links/:hash", patch(update_link).delete(delete_link))
+        .route("/v1/links/:hash/restore", post(restore_link))
+        .route("/v1/links/:hash/stats", get(link_stats))
+        .route("/v1/slugs/:slug/availability", get(slug_availability))
+        .route_layer(from_extractor::<Caller>())
+        .route_layer(api_limit);
 
     let app = Router::new()
-        .route("/:slug", get(visit_link))
+        .route("/:slug", get(visit_link).layer(redirect_limit))
         .route("/health", get(health_endpoint))
-        .route("/v1/link", post(create_link))
-        .route("/v1/links", get(list_links))
+        .merge(api_routes)
         .layer(Extension(pool))
+        .layer(Extension(slugs))
+        .layer(Extension(policy))
+        .layer(Extension(config.links.duplicates))
+        .layer(Extension(config.http))
+        .layer(Extension(config.redirects.clone()))
+        .layer(Extension(not_found))
+        .layer(Extension(config.visits))
+        .layer(Extension(recorder))
+        .layer(Extension(jwt))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(make_span)
@@ -214,7 +978,7 @@ pub async fn launch(config: &AppConfig) -> Result<()> {
     info!("Waiting for SIGTERM/SIGQUIT for graceful shutdown");
 
     Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(async {
             shutdown_rx.await.ok();
         })
@@ -225,5 +989,161 @@ pub async fn launch(config: &AppConfig) -> Result<()> {
         .await
         .expect("error with shutdown handler task");
 
+    if let Some(jwks_refresher) = jwks_refresher {
+        jwks_refresher.abort();
+    }
+
+    // the server, and with it every `VisitRecorder`, has been dropped by now
+    info!("Flushing buffered visits");
+    flusher.await.expect("error with visit recorder task");
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::test_db;
+
+    #[tokio::test]
+    async fn test_manage_links_roles() -> Result<()> {
+        for (role, allowed) in [
+            (Role::Admin, true),
+            (Role::Editor, true),
+            (Role::Viewer, false),
+        ] {
+            let mut req = RequestParts::new(Request::new(()));
+            req.extensions_mut().insert(Caller {
+                principal: Owner::Subject("someone".to_owned()),
+                role,
+            });
+
+            let authorized = Authorized::<ManageLinks>::from_request(&mut req).await;
+            match authorized {
+                Ok(_) => assert!(allowed, "{:?}", role),
+                Err(err) => assert!(
+                    !allowed && matches!(err, AuthError::Forbidden),
+                    "{:?}",
+                    role
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_authorize_link() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+        let owner = Owner::Subject("owner".to_owned());
+        let caller = |subject: &str, role| Caller {
+            principal: Owner::Subject(subject.to_owned()),
+            role,
+        };
+
+        let mut new: NewLink =
+            serde_json::from_value(json!({ "destination": "https://www.google.com" }))?;
+        new.created_by = Some(owner);
+        let link = match Link::create(
+            &mut conn,
+            new,
+            &Slugs::new(&crate::config::LinksConfig::default())?,
+            DuplicatePolicy::Create,
+            &DestinationPolicy::default(),
+        )
+        .await?
+        {
+            Created::Inserted(link) | Created::Existing(link) => link,
+        };
+
+        let other_editor = caller("other", Role::Editor)
+            .authorize_link(&mut conn, &link.hash)
+            .await;
+        assert!(matches!(other_editor, Err(AppError::NotFound)));
+        for allowed in [
+            caller("owner", Role::Editor),
+            caller("other", Role::Admin),
+            caller("other", Role::Viewer),
+        ] {
+            assert!(allowed.authorize_link(&mut conn, &link.hash).await.is_ok());
+        }
+
+        // a missing link is left for the handler to report
+        assert!(caller("other", Role::Editor)
+            .authorize_link(&mut conn, "missing")
+            .await
+            .is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_preview_request() {
+        let abc = || "abc".to_owned();
+
+        assert_eq!(preview_request("abc+", None), Some((abc(), None)));
+        assert_eq!(
+            preview_request("abc+", Some("x=1")),
+            Some((abc(), Some("x=1".to_owned())))
+        );
+        assert_eq!(
+            preview_request("abc", Some("preview=1&x=2")),
+            Some((abc(), Some("x=2".to_owned())))
+        );
+        assert_eq!(
+            preview_request("abc", Some("preview=true")),
+            Some((abc(), None))
+        );
+        assert_eq!(preview_request("abc", Some("preview=0")), None);
+        assert_eq!(preview_request("abc", Some("x=1")), None);
+        assert_eq!(preview_request("abc", None), None);
+    }
+
+    #[test]
+    fn test_client_ip() -> Result<()> {
+        let peer: SocketAddr = "10.0.0.1:4321".parse()?;
+        let mut headers = HeaderMap::new();
+        assert_eq!(client_ip(&headers, peer, true), peer.ip());
+
+        headers.insert("x-forwarded-for", "203.0.113.9, 198.51.100.7".parse()?);
+        assert_eq!(client_ip(&headers, peer, false), peer.ip());
+        assert_eq!(
+            client_ip(&headers, peer, true),
+            "198.51.100.7".parse::<IpAddr>()?
+        );
+
+        headers.append("x-forwarded-for", "192.0.2.4".parse()?);
+        assert_eq!(
+            client_ip(&headers, peer, true),
+            "192.0.2.4".parse::<IpAddr>()?
+        );
+
+        headers.insert("x-forwarded-for", "198.51.100.7, unknown".parse()?);
+        assert_eq!(client_ip(&headers, peer, true), peer.ip());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ip_rate_limit_key() -> Result<()> {
+        let mut extensions = Extensions::new();
+        extensions.insert(ConnectInfo("10.0.0.1:4321".parse::<SocketAddr>()?));
+        extensions.insert(HttpConfig {
+            trust_forwarded_for: true,
+            ..HttpConfig::default()
+        });
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "198.51.100.7".parse()?);
+        let key = ip_rate_limit_key(&extensions, &headers);
+        assert_eq!(key, "ip:198.51.100.7");
+
+        // a client can't escape its bucket by prepending addresses of its own
+        for spoofed in [
+            "203.0.113.9, 198.51.100.7",
+            "192.0.2.1, 192.0.2.2, 198.51.100.7",
+        ] {
+            headers.insert("x-forwarded-for", spoofed.parse()?);
+            assert_eq!(ip_rate_limit_key(&extensions, &headers), key);
+        }
+        Ok(())
+    }
+}
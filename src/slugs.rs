@@ -0,0 +1,597 @@
+//! Generation of the short, opaque `hash` segment of newly created
+//! [`Link`](crate::links::Link)s
+//!
+//! Each available [`SlugStrategy`] is implemented by a [`SlugGenerator`], and
+//! wrapped by [`Slugs`] which additionally filters out any generated hash
+//! containing a blocklisted substring or shadowing one of the app's own routes.
+//!
+//! Generated hashes may collide with existing ones as the `links` table grows,
+//! so the random strategies track how often that happens and lengthen future
+//! hashes once the collision rate crosses a configured threshold.
+
+use std::{
+    fmt::Debug,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use anyhow::{ensure, Result};
+use async_trait::async_trait;
+use rand::seq::SliceRandom;
+use sqlx::PgConnection;
+
+use crate::{
+    config::{LinksConfig, SlugStrategy},
+    links::is_reserved_slug,
+};
+
+/// The base-62 alphabet used for hashes unless otherwise configured
+pub(crate) const DEFAULT_HASH_ALPHABET: &str =
+    "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+/// Number of consecutive blocked or reserved hashes tolerated before giving up on
+/// generating a hash for a single new link
+const MAX_BLOCKED_ATTEMPTS: usize = 100;
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "bold", "brave", "bright", "calm", "clever", "cosmic", "crisp", "daring", "eager",
+    "fancy", "gentle", "golden", "happy", "humble", "jolly", "keen", "lively", "lucky", "mellow",
+    "merry", "nimble", "noble", "plucky", "proud", "quick", "quiet", "rapid", "shiny", "sunny",
+    "swift", "witty",
+];
+
+const NOUNS: &[&str] = &[
+    "badger", "beacon", "canyon", "comet", "falcon", "fern", "forest", "galaxy", "harbor", "heron",
+    "island", "lagoon", "lantern", "maple", "meadow", "meteor", "otter", "panda", "pebble", "pine",
+    "planet", "prairie", "quartz", "river", "robin", "summit", "thunder", "tiger", "tulip",
+    "valley", "walrus", "willow",
+];
+
+/// Generates a random hash of `length` characters drawn from an ASCII `alphabet`
+pub(crate) fn random_hash(alphabet: &[u8], length: usize) -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..length)
+        .filter_map(|_| alphabet.choose(&mut rng))
+        .map(|byte| char::from(*byte))
+        .collect()
+}
+
+/// A strategy for producing candidate hashes for new links
+///
+/// Implementors only propose candidates; uniqueness is ultimately enforced by
+/// the `links_hash` index, and [`SlugGenerator::record`] is informed of each
+/// outcome.
+#[async_trait]
+pub(crate) trait SlugGenerator: Debug + Send + Sync {
+    /// Produces a new candidate hash
+    async fn generate(&self, conn: &mut PgConnection) -> sqlx::Result<String>;
+
+    /// Records whether the most recently generated hash collided with an existing one
+    fn record(&self, _collided: bool) {}
+
+    /// Requests that future hashes be less likely to collide
+    fn grow(&self) {}
+}
+
+/// Tracks the rate of collisions between generated and existing hashes, and
+/// the resulting number of random units (characters, digits) to generate
+#[derive(Debug)]
+struct CollisionTracker {
+    length: AtomicUsize,
+    max_length: usize,
+    threshold: f64,
+    window: u64,
+    attempts: AtomicU64,
+    collisions: AtomicU64,
+}
+
+impl CollisionTracker {
+    fn new(length: usize, max_length: usize, config: &LinksConfig) -> Self {
+        Self {
+            length: AtomicUsize::new(length),
+            max_length,
+            threshold: config.collision_threshold,
+            window: config.collision_window.max(1),
+            attempts: AtomicU64::new(0),
+            collisions: AtomicU64::new(0),
+        }
+    }
+
+    fn length(&self) -> usize {
+        self.length.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, collided: bool) {
+        let attempts = self.attempts.fetch_add(1, Ordering::Relaxed) + 1;
+        let collisions = if collided {
+            self.collisions.fetch_add(1, Ordering::Relaxed) + 1
+        } else {
+            self.collisions.load(Ordering::Relaxed)
+        };
+
+        if attempts < self.window {
+            return;
+        }
+
+        self.attempts.store(0, Ordering::Relaxed);
+        self.collisions.store(0, Ordering::Relaxed);
+
+        #[allow(clippy::cast_precision_loss)]
+        let rate = collisions as f64 / attempts as f64;
+        if rate > self.threshold {
+            self.grow();
+        }
+    }
+
+    fn grow(&self) {
+        let grown = self
+            .length
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |length| {
+                (length < self.max_length).then(|| length + 1)
+            });
+
+        if let Ok(previous) = grown {
+            tracing::info!(length = previous + 1, "increased generated hash length");
+        }
+    }
+}
+
+/// Produces random hashes from a configured alphabet, growing their length as
+/// collisions with previously stored hashes become more frequent
+#[derive(Debug)]
+pub(crate) struct RandomGenerator {
+    alphabet: Vec<u8>,
+    tracker: CollisionTracker,
+}
+
+impl RandomGenerator {
+    /// Builds a `RandomGenerator` from the `[links]` section of the app's configuration
+    pub(crate) fn new(config: &LinksConfig) -> Result<Self> {
+        ensure!(
+            config.hash_length > 0 && config.hash_length <= config.max_hash_length,
+            "links.hash_length must be between 1 and links.max_hash_length"
+        );
+
+        Ok(Self {
+            alphabet: alphabet(config)?,
+            tracker: CollisionTracker::new(config.hash_length, config.max_hash_length, config),
+        })
+    }
+
+    /// The number of characters in hashes currently being generated
+    pub(crate) fn length(&self) -> usize {
+        self.tracker.length()
+    }
+}
+
+#[async_trait]
+impl SlugGenerator for RandomGenerator {
+    async fn generate(&self, _conn: &mut PgConnection) -> sqlx::Result<String> {
+        Ok(random_hash(&self.alphabet, self.length()))
+    }
+
+    fn record(&self, collided: bool) {
+        self.tracker.record(collided);
+    }
+
+    fn grow(&self) {
+        self.tracker.grow();
+    }
+}
+
+/// Encodes values of the `links_hash_seq` Postgres sequence in the configured
+/// alphabet, yielding short codes that increase monotonically
+#[derive(Debug)]
+pub(crate) struct SequenceGenerator {
+    alphabet: Vec<u8>,
+}
+
+impl SequenceGenerator {
+    pub(crate) fn new(config: &LinksConfig) -> Result<Self> {
+        Ok(Self {
+            alphabet: alphabet(config)?,
+        })
+    }
+}
+
+#[async_trait]
+impl SlugGenerator for SequenceGenerator {
+    async fn generate(&self, conn: &mut PgConnection) -> sqlx::Result<String> {
+        let value = next_sequence_value(conn).await?;
+        Ok(encode(value, &self.alphabet))
+    }
+}
+
+/// Encodes values of the `links_hash_seq` Postgres sequence with a
+/// [Hashids](https://hashids.org/)-style salted alphabet, yielding short codes
+/// that don't reveal their ordering but may be decoded back to the sequence value
+#[derive(Debug)]
+pub(crate) struct HashidsGenerator {
+    alphabet: Vec<u8>,
+    salt: Vec<u8>,
+}
+
+impl HashidsGenerator {
+    pub(crate) fn new(config: &LinksConfig) -> Result<Self> {
+        let salt = config.hashids_salt.clone().into_bytes();
+        let mut alphabet = alphabet(config)?;
+        consistent_shuffle(&mut alphabet, &salt);
+
+        Ok(Self { alphabet, salt })
+    }
+
+    /// Derives the alphabet used for the digits of a code beginning with `lottery`
+    fn digits_alphabet(&self, lottery: u8) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(1 + self.salt.len() + self.alphabet.len());
+        buffer.push(lottery);
+        buffer.extend_from_slice(&self.salt);
+        buffer.extend_from_slice(&self.alphabet);
+        buffer.truncate(self.alphabet.len());
+
+        let mut digits = self.alphabet.clone();
+        consistent_shuffle(&mut digits, &buffer);
+        digits
+    }
+
+    /// Produces the code for a given sequence value
+    pub(crate) fn encode(&self, value: u64) -> String {
+        #[allow(clippy::cast_possible_truncation)]
+        let lottery = self.alphabet[(value % self.alphabet.len() as u64) as usize];
+        let digits = self.digits_alphabet(lottery);
+
+        let mut code = String::from(char::from(lottery));
+        code.push_str(&encode(value, &digits));
+        code
+    }
+}
+
+#[async_trait]
+impl SlugGenerator for HashidsGenerator {
+    async fn generate(&self, conn: &mut PgConnection) -> sqlx::Result<String> {
+        let value = next_sequence_value(conn).await?;
+        Ok(self.encode(value))
+    }
+}
+
+/// Produces human-readable hashes such as `brave-otter`, appending a growing
+/// number of random digits as collisions become more frequent
+#[derive(Debug)]
+pub(crate) struct WordPairGenerator {
+    tracker: CollisionTracker,
+}
+
+impl WordPairGenerator {
+    pub(crate) fn new(config: &LinksConfig) -> Self {
+        Self {
+            tracker: CollisionTracker::new(0, config.max_hash_length, config),
+        }
+    }
+}
+
+#[async_trait]
+impl SlugGenerator for WordPairGenerator {
+    async fn generate(&self, _conn: &mut PgConnection) -> sqlx::Result<String> {
+        let mut rng = rand::thread_rng();
+        let adjective = ADJECTIVES.choose(&mut rng).unwrap_or(&"brave");
+        let noun = NOUNS.choose(&mut rng).unwrap_or(&"otter");
+
+        let mut hash = format!("{}-{}", adjective, noun);
+        let digits = self.tracker.length();
+        if digits > 0 {
+            hash.push('-');
+            hash.push_str(&random_hash(b"0123456789", digits));
+        }
+
+        Ok(hash)
+    }
+
+    fn record(&self, collided: bool) {
+        self.tracker.record(collided);
+    }
+
+    fn grow(&self) {
+        self.tracker.grow();
+    }
+}
+
+/// The configured [`SlugGenerator`], filtered through a blocklist of
+/// substrings which generated hashes must never contain and the reserved
+/// slugs which would be shadowed by the app's routes
+#[derive(Debug)]
+pub(crate) struct Slugs {
+    generator: Box<dyn SlugGenerator>,
+    blocklist: Vec<String>,
+}
+
+impl Slugs {
+    /// Builds the [`SlugGenerator`] selected by the `[links]` section of the
+    /// app's configuration
+    pub(crate) fn new(config: &LinksConfig) -> Result<Self> {
+        let generator: Box<dyn SlugGenerator> = match config.strategy {
+            SlugStrategy::Random => Box::new(RandomGenerator::new(config)?),
+            SlugStrategy::Sequence => Box::new(SequenceGenerator::new(config)?),
+            SlugStrategy::Hashids => Box::new(HashidsGenerator::new(config)?),
+            SlugStrategy::WordPair => Box::new(WordPairGenerator::new(config)),
+        };
+
+        Ok(Self {
+            generator,
+            blocklist: config
+                .blocklist
+                .iter()
+                .map(|word| word.to_lowercase())
+                .collect(),
+        })
+    }
+
+    /// Whether `hash` contains any blocklisted substring, ignoring case
+    pub(crate) fn is_blocked(&self, hash: &str) -> bool {
+        let hash = hash.to_lowercase();
+        self.blocklist.iter().any(|word| hash.contains(word))
+    }
+
+    /// Generates a candidate hash which contains no blocklisted substrings and
+    /// isn't reserved, or `None` if the generator repeatedly produced only
+    /// blocked or reserved hashes
+    pub(crate) async fn generate(&self, conn: &mut PgConnection) -> sqlx::Result<Option<String>> {
+        for _ in 0..MAX_BLOCKED_ATTEMPTS {
+            let hash = self.generator.generate(conn).await?;
+            if !self.is_blocked(&hash) && !is_reserved_slug(&hash) {
+                return Ok(Some(hash));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// See [`SlugGenerator::record`]
+    pub(crate) fn record(&self, collided: bool) {
+        self.generator.record(collided);
+    }
+
+    /// See [`SlugGenerator::grow`]
+    pub(crate) fn grow(&self) {
+        self.generator.grow();
+    }
+}
+
+/// Validates and deduplicates the configured hash alphabet, preserving the
+/// order in which characters first appear
+fn alphabet(config: &LinksConfig) -> Result<Vec<u8>> {
+    let mut alphabet = Vec::with_capacity(config.hash_alphabet.len());
+    for byte in config.hash_alphabet.bytes() {
+        if !alphabet.contains(&byte) {
+            alphabet.push(byte);
+        }
+    }
+
+    ensure!(
+        alphabet.len() >= 2,
+        "links.hash_alphabet must contain at least two distinct characters"
+    );
+    ensure!(
+        alphabet
+            .iter()
+            .all(|c| c.is_ascii_alphanumeric() || *c == b'-' || *c == b'_'),
+        "links.hash_alphabet may only contain letters, digits, `-` or `_`"
+    );
+
+    Ok(alphabet)
+}
+
+async fn next_sequence_value(conn: &mut PgConnection) -> sqlx::Result<u64> {
+    let value = sqlx::query_scalar!(r#"SELECT nextval('links_hash_seq') AS "value!""#)
+        .fetch_one(conn)
+        .await?;
+
+    // the sequence is declared with a minimum value of 1
+    #[allow(clippy::cast_sign_loss)]
+    Ok(value as u64)
+}
+
+/// Positional encoding of `value` using `alphabet` as its digits
+fn encode(mut value: u64, alphabet: &[u8]) -> String {
+    let base = alphabet.len() as u64;
+    let mut digits = Vec::new();
+
+    loop {
+        #[allow(clippy::cast_possible_truncation)]
+        digits.push(alphabet[(value % base) as usize]);
+        value /= base;
+        if value == 0 {
+            break;
+        }
+    }
+
+    digits.iter().rev().map(|byte| char::from(*byte)).collect()
+}
+
+/// Deterministically shuffles `alphabet` based on `salt`, as in Hashids
+fn consistent_shuffle(alphabet: &mut [u8], salt: &[u8]) {
+    if salt.is_empty() {
+        return;
+    }
+
+    let mut v = 0;
+    let mut p = 0;
+    for i in (1..alphabet.len()).rev() {
+        v %= salt.len();
+        let n = usize::from(salt[v]);
+        p += n;
+        alphabet.swap(i, (n + v + p) % i);
+        v += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::test_db;
+
+    impl HashidsGenerator {
+        /// Recovers the sequence value a code was produced from, if it is a valid code
+        fn decode(&self, code: &str) -> Option<u64> {
+            let (&lottery, rest) = code.as_bytes().split_first()?;
+            if rest.is_empty() || !self.alphabet.contains(&lottery) {
+                return None;
+            }
+
+            let value = decode(rest, &self.digits_alphabet(lottery))?;
+            (self.encode(value) == code).then(|| value)
+        }
+    }
+
+    /// Inverse of [`encode`], returning `None` for unknown digits or overflow
+    fn decode(digits: &[u8], alphabet: &[u8]) -> Option<u64> {
+        let base = alphabet.len() as u64;
+
+        digits.iter().try_fold(0_u64, |value, digit| {
+            let position = alphabet.iter().position(|c| c == digit)? as u64;
+            value.checked_mul(base)?.checked_add(position)
+        })
+    }
+
+    fn config(hash_length: usize, max_hash_length: usize) -> LinksConfig {
+        LinksConfig {
+            hash_length,
+            max_hash_length,
+            collision_threshold: 0.5,
+            collision_window: 4,
+            ..LinksConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_random() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.acquire().await?;
+        let generator = RandomGenerator::new(&LinksConfig {
+            hash_alphabet: "ab".to_owned(),
+            ..config(8, 8)
+        })?;
+        let hash = generator.generate(&mut conn).await?;
+
+        assert_eq!(hash.len(), 8);
+        assert!(hash.chars().all(|c| c == 'a' || c == 'b'));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_alphabet() {
+        let generator = RandomGenerator::new(&LinksConfig {
+            hash_alphabet: "a/b".to_owned(),
+            ..config(5, 12)
+        });
+
+        assert!(generator.is_err());
+    }
+
+    #[test]
+    fn test_grows_on_collision_rate() -> Result<()> {
+        let generator = RandomGenerator::new(&config(5, 6))?;
+
+        for collided in [false, true, false, false] {
+            generator.record(collided);
+        }
+        assert_eq!(generator.length(), 5);
+
+        for collided in [true, true, true, false] {
+            generator.record(collided);
+        }
+        assert_eq!(generator.length(), 6);
+
+        for _ in 0..4 {
+            generator.record(true);
+        }
+        assert_eq!(generator.length(), 6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sequence() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.acquire().await?;
+        let generator = SequenceGenerator::new(&LinksConfig::default())?;
+
+        let first = generator.generate(&mut conn).await?;
+        let second = generator.generate(&mut conn).await?;
+
+        assert_ne!(first, second);
+        assert!(first.len() <= second.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashids_round_trip() -> Result<()> {
+        let generator = HashidsGenerator::new(&LinksConfig {
+            hashids_salt: "pepper".to_owned(),
+            ..LinksConfig::default()
+        })?;
+
+        for value in [1, 2, 61, 62, 12_345, u64::MAX] {
+            let code = generator.encode(value);
+            assert_eq!(generator.decode(&code), Some(value), "{}", code);
+        }
+        assert_ne!(
+            generator.encode(1),
+            encode(1, DEFAULT_HASH_ALPHABET.as_bytes())
+        );
+        assert_eq!(generator.decode("/"), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_word_pair() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.acquire().await?;
+        let generator = WordPairGenerator::new(&LinksConfig::default());
+
+        let hash = generator.generate(&mut conn).await?;
+        assert_eq!(hash.split('-').count(), 2);
+
+        generator.grow();
+        let hash = generator.generate(&mut conn).await?;
+        let suffix = hash.rsplit('-').next().unwrap_or_default();
+        assert!(suffix.len() == 1 && suffix.chars().all(|c| c.is_ascii_digit()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blocklist() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.acquire().await?;
+        let slugs = Slugs::new(&LinksConfig {
+            hash_alphabet: "ab".to_owned(),
+            blocklist: vec!["A".to_owned()],
+            ..config(1, 1)
+        })?;
+
+        assert!(slugs.is_blocked("cAb"));
+        for _ in 0..10 {
+            assert_eq!(slugs.generate(&mut conn).await?.as_deref(), Some("b"));
+        }
+
+        let slugs = Slugs::new(&LinksConfig {
+            hash_alphabet: "ab".to_owned(),
+            blocklist: vec!["a".to_owned(), "b".to_owned()],
+            ..config(1, 1)
+        })?;
+        assert_eq!(slugs.generate(&mut conn).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reserved() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.acquire().await?;
+        let slugs = Slugs::new(&LinksConfig {
+            hash_alphabet: "v1".to_owned(),
+            ..config(2, 2)
+        })?;
+
+        for _ in 0..20 {
+            assert_ne!(slugs.generate(&mut conn).await?.as_deref(), Some("v1"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,419 @@
+//! Core database interactions around recorded visits to [`Link`]s
+
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+use tracing::{instrument, warn};
+use uuid::Uuid;
+
+use crate::{config::VisitsConfig, links::Link};
+
+/// A single redirect through a [`Link`], as observed by `visit_link`
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct NewVisit {
+    pub(crate) link_id: Uuid,
+    pub(crate) visited_at: DateTime<Utc>,
+    /// the `Referer` header sent by the visitor, if any
+    pub(crate) referrer: Option<String>,
+    /// the `User-Agent` header sent by the visitor, if any
+    pub(crate) user_agent: Option<String>,
+    /// the visitor's IP address, possibly anonymized by [`anonymize_ip`]
+    pub(crate) client_ip: Option<IpAddr>,
+}
+
+impl NewVisit {
+    /// Records many visits in the database with a single multi-row `INSERT`,
+    /// adding them to the `clicks` of each visited [`Link`]
+    #[instrument(skip(conn, visits), fields(count = visits.len()))]
+    // the casts tell `sqlx::query!` that the `text[]` arrays may contain NULLs
+    #[allow(trivial_casts)]
+    pub(crate) async fn insert_batch(
+        conn: &mut PgConnection,
+        visits: Vec<NewVisit>,
+    ) -> sqlx::Result<()> {
+        let mut link_ids = Vec::with_capacity(visits.len());
+        let mut visited_ats = Vec::with_capacity(visits.len());
+        let mut referrers = Vec::with_capacity(visits.len());
+        let mut user_agents = Vec::with_capacity(visits.len());
+        let mut client_ips = Vec::with_capacity(visits.len());
+
+        for visit in visits {
+            link_ids.push(visit.link_id);
+            visited_ats.push(visit.visited_at);
+            referrers.push(visit.referrer);
+            user_agents.push(visit.user_agent);
+            client_ips.push(visit.client_ip.map(|ip| ip.to_string()));
+        }
+
+        sqlx::query!(
+            r#"WITH inserted AS (
+                INSERT INTO visits (link_id, visited_at, referrer, user_agent, client_ip)
+                SELECT * FROM UNNEST($1::uuid[], $2::timestamptz[], $3::text[], $4::text[],
+                    $5::text[])
+                RETURNING link_id
+            )
+            UPDATE links SET clicks = links.clicks + counted.clicks
+            FROM (SELECT link_id, count(*) AS clicks FROM inserted GROUP BY link_id) counted
+            WHERE links.id = counted.link_id"#,
+            &link_ids,
+            &visited_ats,
+            &referrers as &[Option<String>],
+            &user_agents as &[Option<String>],
+            &client_ips as &[Option<String>]
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Hands [`NewVisit`]s off to a background task which writes them to the
+/// database in batches, keeping the insert off of the redirect's hot path
+///
+/// Visits are buffered in a bounded channel. When it is full, further visits
+/// are dropped and counted rather than slowing down redirects. Visits in a
+/// batch which fails to be written are counted as dropped too, and the
+/// background task logs how many were dropped at most once per
+/// [`DROPPED_REPORT_INTERVAL`].
+#[derive(Clone, Debug)]
+pub(crate) struct VisitRecorder {
+    sender: mpsc::Sender<NewVisit>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl VisitRecorder {
+    /// Spawns the background task which flushes recorded visits to `pool`
+    ///
+    /// The task drains any buffered visits and exits once every clone of the
+    /// returned `VisitRecorder` has been dropped, so awaiting the returned
+    /// handle after shutting down the server ensures no visits are lost.
+    pub(crate) fn spawn(pool: PgPool, config: &VisitsConfig) -> (Self, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel(config.buffer_size.max(1));
+        let dropped = Arc::new(AtomicU64::default());
+        let flusher = tokio::spawn(flush(
+            pool,
+            receiver,
+            Arc::clone(&dropped),
+            config.batch_size.max(1),
+            Duration::from_millis(config.flush_interval_ms),
+        ));
+        let recorder = Self { sender, dropped };
+
+        (recorder, flusher)
+    }
+
+    /// Buffers a visit to be written by the background task without waiting
+    pub(crate) fn record(&self, visit: NewVisit) {
+        if self.sender.try_send(visit).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Background loop of [`VisitRecorder`], writing a batch once it holds
+/// `batch_size` visits or its oldest visit has waited for `interval`
+///
+/// Visits dropped by [`VisitRecorder::record`] or in failed batches are
+/// logged in aggregate, at most once per [`DROPPED_REPORT_INTERVAL`] and once
+/// more before exiting.
+async fn flush(
+    pool: PgPool,
+    mut receiver: mpsc::Receiver<NewVisit>,
+    dropped: Arc<AtomicU64>,
+    batch_size: usize,
+    interval: Duration,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut reported = 0;
+    let mut last_report = Instant::now();
+
+    while let Some(visit) = receiver.recv().await {
+        batch.push(visit);
+        let deadline = Instant::now() + interval;
+
+        while batch.len() < batch_size {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(visit)) => batch.push(visit),
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        let count = batch.len();
+        let result = match pool.acquire().await {
+            Ok(mut conn) => NewVisit::insert_batch(&mut conn, batch.split_off(0)).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            warn!(error = %err, dropped_visits = count, "could not record visits");
+            dropped.fetch_add(count as u64, Ordering::Relaxed);
+        }
+
+        if last_report.elapsed() >= DROPPED_REPORT_INTERVAL {
+            reported = report_dropped(&dropped, reported);
+            last_report = Instant::now();
+        }
+    }
+
+    report_dropped(&dropped, reported);
+}
+
+/// Logs how many visits were dropped since `reported` had been, returning
+/// the new total
+fn report_dropped(dropped: &AtomicU64, reported: u64) -> u64 {
+    let total = dropped.load(Ordering::Relaxed);
+    if total > reported {
+        warn!(
+            dropped_visits = total - reported,
+            total_dropped_visits = total,
+            "dropped visits"
+        );
+    }
+
+    total
+}
+
+/// The shortest time between two logs of dropped visits by [`VisitRecorder`]
+const DROPPED_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Removes the host-identifying portion of an IP address, keeping the `/24`
+/// network of an IPv4 address or the `/48` network of an IPv6 address
+pub(crate) fn anonymize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => {
+            let mut segments = ip.segments();
+            segments[3..].fill(0);
+            IpAddr::from(segments)
+        }
+    }
+}
+
+/// Available widths of the time buckets in [`LinkStats::series`]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Bucket {
+    Hour,
+    Day,
+    Week,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Self::Day
+    }
+}
+
+impl Bucket {
+    /// The corresponding field name for Postgres' `date_trunc`
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Hour => "hour",
+            Self::Day => "day",
+            Self::Week => "week",
+        }
+    }
+}
+
+/// The number of visits to a [`Link`] within a single time bucket
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct BucketCount {
+    /// the beginning of the bucket, in UTC
+    start: DateTime<Utc>,
+    clicks: i64,
+}
+
+/// Aggregated visit statistics for a single [`Link`]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct LinkStats {
+    hash: String,
+    /// all visits ever recorded
+    total_clicks: i64,
+    /// distinct client networks among all visits ever recorded, which are the
+    /// `/24` or `/48` networks of visitors when `anonymize_ip` is set, and
+    /// otherwise their individual addresses
+    unique_networks: i64,
+    bucket: Bucket,
+    /// visits per `bucket`, oldest first, omitting buckets without any visits
+    series: Vec<BucketCount>,
+}
+
+impl LinkStats {
+    /// Aggregates the recorded visits to `link`, with a time series limited to
+    /// visits after `since` if provided
+    #[instrument(skip(conn, link), fields(hash = %link.hash))]
+    pub(crate) async fn for_link(
+        conn: &mut PgConnection,
+        link: &Link,
+        bucket: Bucket,
+        since: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<Self> {
+        let totals = sqlx::query!(
+            r#"SELECT count(*) AS "total_clicks!", count(DISTINCT client_ip) AS "unique_networks!"
+            FROM visits WHERE link_id = $1"#,
+            link.id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let series = sqlx::query_as!(
+            BucketCount,
+            r#"SELECT date_trunc($2, visited_at, 'UTC') AS "start!", count(*) AS "clicks!"
+            FROM visits
+            WHERE link_id = $1 AND ($3::timestamptz IS NULL OR visited_at >= $3)
+            GROUP BY 1
+            ORDER BY 1"#,
+            link.id,
+            bucket.as_str(),
+            since
+        )
+        .fetch_all(conn)
+        .await?;
+
+        Ok(Self {
+            hash: link.hash.clone(),
+            total_clicks: totals.total_clicks,
+            unique_networks: totals.unique_networks,
+            bucket,
+            series,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::test_db;
+    use anyhow::Result;
+    use chrono::{Duration, TimeZone};
+    use url::Url;
+
+    #[test]
+    fn test_anonymize_ip() -> Result<()> {
+        assert_eq!(
+            anonymize_ip("203.0.113.42".parse()?),
+            "203.0.113.0".parse::<IpAddr>()?
+        );
+        assert_eq!(
+            anonymize_ip("2001:db8:85a3:8d3:1319:8a2e:370:7348".parse()?),
+            "2001:db8:85a3::".parse::<IpAddr>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_full() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let recorder = VisitRecorder {
+            sender,
+            dropped: Arc::new(AtomicU64::default()),
+        };
+        let visit = NewVisit {
+            link_id: Uuid::new_v4(),
+            visited_at: Utc::now(),
+            referrer: None,
+            user_agent: None,
+            client_ip: None,
+        };
+
+        for _ in 0..3 {
+            recorder.record(visit.clone());
+        }
+        assert_eq!(recorder.dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(receiver.try_recv(), Ok(visit));
+    }
+
+    #[tokio::test]
+    async fn test_insert_batch() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let link =
+            Link::insert(&mut conn, Link::new(&Url::parse("https://www.google.com")?)).await?;
+        let visits = (0..3)
+            .map(|_| NewVisit {
+                link_id: link.id,
+                visited_at: Utc::now(),
+                referrer: None,
+                user_agent: None,
+                client_ip: None,
+            })
+            .collect();
+        NewVisit::insert_batch(&mut conn, visits).await?;
+
+        let stats = LinkStats::for_link(&mut conn, &link, Bucket::Day, None).await?;
+        assert_eq!(stats.total_clicks, 3);
+        assert_eq!(stats.unique_networks, 0);
+
+        let link = Link::get_by_hash(&mut conn, &link.hash)
+            .await?
+            .expect("link should exist");
+        assert_eq!(link.clicks, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_link_stats() -> Result<()> {
+        let pool = test_db().await?;
+        let mut conn = pool.begin().await?;
+
+        let link =
+            Link::insert(&mut conn, Link::new(&Url::parse("https://www.google.com")?)).await?;
+        let morning = Utc.ymd(2022, 9, 1).and_hms(9, 30, 0);
+
+        let mut visits = Vec::new();
+        for (offset, ip) in [(0, "192.0.2.1"), (10, "192.0.2.1"), (90, "192.0.2.2")] {
+            visits.push(NewVisit {
+                link_id: link.id,
+                visited_at: morning + Duration::minutes(offset),
+                referrer: None,
+                user_agent: Some("curl/7.84.0".to_owned()),
+                client_ip: Some(ip.parse()?),
+            });
+        }
+        NewVisit::insert_batch(&mut conn, visits).await?;
+
+        let stats = LinkStats::for_link(&mut conn, &link, Bucket::Hour, None).await?;
+        assert_eq!(stats.total_clicks, 3);
+        assert_eq!(stats.unique_networks, 2);
+        assert_eq!(
+            stats.series,
+            vec![
+                BucketCount {
+                    start: Utc.ymd(2022, 9, 1).and_hms(9, 0, 0),
+                    clicks: 2
+                },
+                BucketCount {
+                    start: Utc.ymd(2022, 9, 1).and_hms(11, 0, 0),
+                    clicks: 1
+                },
+            ]
+        );
+
+        let since = Some(morning + Duration::hours(1));
+        let stats = LinkStats::for_link(&mut conn, &link, Bucket::Day, since).await?;
+        assert_eq!(stats.total_clicks, 3);
+        assert_eq!(
+            stats.series,
+            vec![BucketCount {
+                start: Utc.ymd(2022, 9, 1).and_hms(0, 0, 0),
+                clicks: 1
+            }]
+        );
+        Ok(())
+    }
+}